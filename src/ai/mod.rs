@@ -1,9 +1,31 @@
-use crate::models::Conversation;
+use crate::models::{usage::Usage, Conversation, UserMemory};
 
 pub mod openai;
 
+pub struct Reply {
+    pub content: String,
+    /// Facts the model chose to remember about the user while replying
+    pub remembered: Vec<String>,
+    /// Everything spent on this reply, including tool call rounds
+    pub usage: Usage,
+}
+
+/// `memory` is `None` where the user's memories shouldn't be used, nor new facts saved
 pub trait Model {
-    async fn reply(&self, conversation: &Conversation) -> anyhow::Result<String>;
+    async fn reply(
+        &self,
+        conversation: &Conversation,
+        memory: Option<&UserMemory>,
+    ) -> anyhow::Result<Reply>;
+    /// Like `reply`, with an extra instruction from the bot that isn't part of the conversation
+    async fn reply_with_instruction(
+        &self,
+        conversation: &Conversation,
+        memory: Option<&UserMemory>,
+        instruction: &str,
+    ) -> anyhow::Result<Reply>;
+    #[allow(dead_code)]
     async fn description(&self, conversation: &Conversation) -> anyhow::Result<String>;
-    async fn my_turn(&self, conversation: &Conversation) -> anyhow::Result<bool>;
+    /// Whether the bot should answer in a group, and what it cost to decide
+    async fn my_turn(&self, conversation: &Conversation) -> anyhow::Result<(bool, Usage)>;
 }
@@ -1,17 +1,33 @@
-use crate::models::{ChatMessage, Conversation};
-use crate::{ai::Model, Role};
+use crate::metrics::METRICS;
+use crate::models::{usage::Usage, ChatMessage, Conversation, UserMemory, MAX_MEMORIES};
+use crate::{
+    ai::{Model, Reply},
+    Role,
+};
 
 use anyhow::Context;
 use async_openai::types::{
-    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestSystemMessageArgs,
-    ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestToolMessageArgs,
+    ChatCompletionRequestUserMessageArgs, ChatCompletionResponseMessage, ChatCompletionTool,
+    ChatCompletionToolArgs, ChatCompletionToolChoiceOption, CreateChatCompletionRequestArgs,
+    FunctionObjectArgs,
 };
 use async_openai::{config::OpenAIConfig, Client};
+use secrecy::{ExposeSecret, SecretString};
+use std::time::Instant;
+
+/// How many rounds of tool calls the model may make before it has to answer
+const MAX_TOOL_ROUNDS: usize = 3;
 
 #[derive(Clone, Debug)]
 pub struct OpenAIModel {
     client: Client<OpenAIConfig>,
+    /// The backend's name in the config, for metrics
+    name: String,
     model: String,
+    /// Used for conversations without their own system message
+    default_system: Option<String>,
 }
 
 impl OpenAIModel {
@@ -19,24 +35,40 @@ impl OpenAIModel {
         let config = OpenAIConfig::new().with_api_base(api_url);
         Self {
             client: Client::with_config(config),
+            name: "openai".into(),
             model,
+            default_system: None,
         }
     }
-    pub fn new_with_token(api_url: String, model: String, token: String) -> Self {
+    /// The client keeps the token in a `Secret` too, so it never shows up in `Debug` output
+    pub fn new_with_token(api_url: String, model: String, token: &SecretString) -> Self {
         let config = OpenAIConfig::new()
             .with_api_base(api_url)
-            .with_api_key(token);
+            .with_api_key(token.expose_secret());
         Self {
             client: Client::with_config(config),
+            name: "openai".into(),
             model,
+            default_system: None,
         }
     }
+    pub fn with_name(mut self, name: String) -> Self {
+        self.name = name;
+        self
+    }
+    pub fn with_default_system(mut self, system: Option<String>) -> Self {
+        self.default_system = system;
+        self
+    }
 
-    async fn reply_with_system(
-        &self,
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn build_messages(
         system: Option<&str>,
         conversation: &[ChatMessage],
-    ) -> anyhow::Result<String> {
+    ) -> Vec<ChatCompletionRequestMessage> {
         let mut msgs = Vec::with_capacity(conversation.len() + usize::from(system.is_some()));
         if let Some(system) = &system {
             msgs.push(
@@ -62,43 +94,223 @@ impl OpenAIModel {
                     .into(),
             }
         }));
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(self.model.clone())
-            .messages(msgs)
-            .build()
-            .unwrap();
+        msgs
+    }
 
-        self.client
-            .chat()
-            .create(request)
-            .await?
+    async fn complete(
+        &self,
+        msgs: Vec<ChatCompletionRequestMessage>,
+        tools: Vec<ChatCompletionTool>,
+        allow_tool_calls: bool,
+    ) -> anyhow::Result<(ChatCompletionResponseMessage, Usage)> {
+        let mut request = CreateChatCompletionRequestArgs::default();
+        request.model(self.model.clone()).messages(msgs);
+        if !tools.is_empty() {
+            request.tools(tools);
+            if !allow_tool_calls {
+                request.tool_choice(ChatCompletionToolChoiceOption::None);
+            }
+        }
+        let request = request.build().unwrap();
+
+        let start = Instant::now();
+        let response = self.client.chat().create(request).await;
+        METRICS.backend_request(&self.name, &self.model, start.elapsed(), response.is_ok());
+        let response = response?;
+        // Not every OpenAI compatible server reports usage, those count as requests only
+        let usage = response.usage.map_or(
+            Usage {
+                requests: 1,
+                ..Usage::default()
+            },
+            |usage| Usage {
+                requests: 1,
+                prompt_tokens: usage.prompt_tokens.into(),
+                completion_tokens: usage.completion_tokens.into(),
+            },
+        );
+        METRICS.tokens(&self.name, &self.model, usage);
+        let message = response
             .choices
             .into_iter()
             .nth(0)
-            .and_then(|msg| msg.message.content)
-            .context("OpenAI client returned empty response!")
+            .map(|choice| choice.message)
+            .context("OpenAI client returned empty response!")?;
+        Ok((message, usage))
+    }
+
+    async fn reply_with_system(
+        &self,
+        system: Option<&str>,
+        conversation: &[ChatMessage],
+    ) -> anyhow::Result<(String, Usage)> {
+        let (message, usage) = self
+            .complete(Self::build_messages(system, conversation), vec![], false)
+            .await?;
+        let content = message
+            .content
+            .context("OpenAI client returned empty response!")?;
+        Ok((content, usage))
+    }
+
+    async fn reply_with_tools(
+        &self,
+        conversation: &Conversation,
+        memory: Option<&UserMemory>,
+        instruction: Option<&str>,
+    ) -> anyhow::Result<Reply> {
+        let system = conversation
+            .system
+            .as_deref()
+            .or(self.default_system.as_deref());
+        let system = match memory {
+            Some(memory) => memory.augment_system(system),
+            None => system.map(Into::into),
+        };
+        let tools = memory
+            .map(|_| Self::memory_tool())
+            .into_iter()
+            .collect::<Vec<_>>();
+        let mut msgs = Self::build_messages(system.as_deref(), &conversation.messages);
+        if let Some(instruction) = instruction {
+            msgs.push(
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(instruction)
+                    .build()
+                    .unwrap()
+                    .into(),
+            );
+        }
+        let mut remembered = vec![];
+        // What the memory will hold once the facts remembered so far are saved
+        let mut known = memory.cloned().unwrap_or_default();
+        let mut usage = Usage::default();
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let (response, round_usage) = self.complete(msgs.clone(), tools.clone(), true).await?;
+            usage += round_usage;
+            let tool_calls = response.tool_calls.unwrap_or_default();
+            if tool_calls.is_empty() {
+                let content = response
+                    .content
+                    .context("OpenAI client returned empty response!")?;
+                return Ok(Reply {
+                    content,
+                    remembered,
+                    usage,
+                });
+            }
+            msgs.push(
+                ChatCompletionRequestAssistantMessageArgs::default()
+                    .tool_calls(tool_calls.clone())
+                    .build()
+                    .unwrap()
+                    .into(),
+            );
+            for call in tool_calls {
+                let result = if call.function.name == "remember" {
+                    let fact = serde_json::from_str::<serde_json::Value>(&call.function.arguments)
+                        .ok()
+                        .and_then(|args| args["fact"].as_str().map(String::from))
+                        .filter(|fact| !fact.trim().is_empty());
+                    match fact {
+                        None => "Error: missing `fact` argument",
+                        Some(fact) if known.remember(&fact) => {
+                            remembered.push(fact);
+                            "Remembered."
+                        }
+                        Some(_) if known.facts.len() >= MAX_MEMORIES => {
+                            "Not saved, the memory is full. The user can make room with /forget."
+                        }
+                        Some(_) => "Already remembered.",
+                    }
+                } else {
+                    "Error: unknown tool"
+                };
+                msgs.push(
+                    ChatCompletionRequestToolMessageArgs::default()
+                        .content(result)
+                        .tool_call_id(call.id)
+                        .build()
+                        .unwrap()
+                        .into(),
+                );
+            }
+        }
+        // Out of tool rounds, force a plain answer
+        let (response, round_usage) = self.complete(msgs, tools, false).await?;
+        usage += round_usage;
+        let content = response
+            .content
+            .context("OpenAI client returned empty response!")?;
+        Ok(Reply {
+            content,
+            remembered,
+            usage,
+        })
+    }
+    fn memory_tool() -> ChatCompletionTool {
+        ChatCompletionToolArgs::default()
+            .function(
+                FunctionObjectArgs::default()
+                    .name("remember")
+                    .description("Save a short, long-lasting fact about the user (their preferences, job, projects...) so you still know it in future conversations.")
+                    .parameters(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "fact": {
+                                "type": "string",
+                                "description": "The fact to remember, e.g. \"prefers Rust examples\"",
+                            },
+                        },
+                        "required": ["fact"],
+                    }))
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap()
     }
 }
 
 impl Model for OpenAIModel {
-    async fn reply(&self, conversation: &Conversation) -> anyhow::Result<String> {
-        self.reply_with_system(conversation.system.as_deref(), &conversation.messages)
+    async fn reply(
+        &self,
+        conversation: &Conversation,
+        memory: Option<&UserMemory>,
+    ) -> anyhow::Result<Reply> {
+        self.reply_with_tools(conversation, memory, None).await
+    }
+    async fn reply_with_instruction(
+        &self,
+        conversation: &Conversation,
+        memory: Option<&UserMemory>,
+        instruction: &str,
+    ) -> anyhow::Result<Reply> {
+        self.reply_with_tools(conversation, memory, Some(instruction))
             .await
     }
     async fn description(&self, conversation: &Conversation) -> anyhow::Result<String> {
         const DESCRIPTION_SYSTEM_MSG: &str = "Describe the following chat dialogue. Be as concise as possible, limiting your summary to one sentence if at all possible.";
-        self.reply_with_system(Some(DESCRIPTION_SYSTEM_MSG), &conversation.messages)
-            .await
+        let (description, _) = self
+            .reply_with_system(Some(DESCRIPTION_SYSTEM_MSG), &conversation.messages)
+            .await?;
+        Ok(description)
     }
 
-    async fn my_turn(&self, conversation: &Conversation) -> anyhow::Result<bool> {
+    async fn my_turn(&self, conversation: &Conversation) -> anyhow::Result<(bool, Usage)> {
         const MY_TURN_SYSTEM_MSG: &str = "Read the conversation below and reply with one word: YES if it is your turn to respond, and NO if it is not your turn to respond.";
-        let reply = self
+        let (reply, usage) = self
             .reply_with_system(Some(MY_TURN_SYSTEM_MSG), &conversation.messages)
             .await?;
         match reply.as_str() {
-            "YES" => Ok(true),
-            "NO" => Ok(false),
+            "YES" => {
+                METRICS.my_turn(true);
+                Ok((true, usage))
+            }
+            "NO" => {
+                METRICS.my_turn(false);
+                Ok((false, usage))
+            }
             _ => Err(anyhow::anyhow!(
                 "Got non YES/NO answer for is_my_turn: {reply}"
             )),
@@ -0,0 +1,214 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use teloxide::prelude::*;
+
+use super::Command;
+use crate::config::AccessConfig;
+
+const DEFAULT_UNAUTHORIZED_MESSAGE: &str =
+    "Sorry, this bot is private. Ask its owner for an invite link!";
+const INVITE_CODE_LENGTH: usize = 12;
+
+/// Who is allowed to use the bot at all
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct AccessList {
+    /// When set, only allowed users and chats can use the bot. Set from the config rather than
+    /// saved.
+    #[serde(skip)]
+    pub allowlist_only: bool,
+    #[serde(skip)]
+    pub unauthorized_message: Option<String>,
+    /// Allowed in the config, kept apart from /allow so a config reload can take them back
+    #[serde(skip)]
+    configured_users: BTreeSet<UserId>,
+    #[serde(skip)]
+    configured_chats: BTreeSet<ChatId>,
+    pub allowed_users: BTreeSet<UserId>,
+    pub allowed_chats: BTreeSet<ChatId>,
+    pub blocked_users: BTreeSet<UserId>,
+    pub blocked_chats: BTreeSet<ChatId>,
+    /// Invite code => remaining uses
+    pub invite_codes: BTreeMap<String, usize>,
+}
+
+impl AccessList {
+    pub fn is_allowed(&self, user_id: Option<UserId>, chat_id: ChatId) -> bool {
+        if self.blocked_chats.contains(&chat_id)
+            || user_id.is_some_and(|id| self.blocked_users.contains(&id))
+        {
+            return false;
+        }
+        !self.allowlist_only
+            || self.allowed_chats.contains(&chat_id)
+            || self.configured_chats.contains(&chat_id)
+            || user_id.is_some_and(|id| {
+                self.allowed_users.contains(&id) || self.configured_users.contains(&id)
+            })
+    }
+
+    /// Replaces the rules from the config, keeping the ones from commands and invites
+    pub fn apply_config(&mut self, config: &AccessConfig) {
+        self.allowlist_only = config.allowlist_only
+            || !config.allowed_users.is_empty()
+            || !config.allowed_chats.is_empty();
+        self.configured_users = config.allowed_users.iter().copied().map(UserId).collect();
+        self.configured_chats = config.allowed_chats.iter().copied().map(ChatId).collect();
+        self.unauthorized_message
+            .clone_from(&config.unauthorized_message);
+    }
+
+    pub fn unauthorized_message(&self) -> &str {
+        self.unauthorized_message
+            .as_deref()
+            .unwrap_or(DEFAULT_UNAUTHORIZED_MESSAGE)
+    }
+
+    /// Allows the user if the invite code is valid, using it up. Blocked users can't use
+    /// invites, only the owner can unblock them.
+    pub fn redeem_invite(&mut self, code: &str, user_id: UserId) -> bool {
+        if self.blocked_users.contains(&user_id) {
+            return false;
+        }
+        // Codes are removed when they run out, but the saved file could still have one at 0
+        let Some(uses) = self.invite_codes.get_mut(code).filter(|uses| **uses > 0) else {
+            return false;
+        };
+        *uses -= 1;
+        if *uses == 0 {
+            self.invite_codes.remove(code);
+        }
+        self.allowed_users.insert(user_id);
+        true
+    }
+
+    fn new_invite(&mut self, uses: usize) -> String {
+        let code: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(INVITE_CODE_LENGTH)
+            .map(char::from)
+            .collect();
+        self.invite_codes.insert(code.clone(), uses.max(1));
+        code
+    }
+}
+
+/// Who an /allow or /block applies to
+#[derive(Clone, Copy, Debug)]
+enum Target {
+    User(UserId),
+    Chat(ChatId),
+}
+
+/// Parses an /allow or /block argument. Negative IDs are group chats, positive ones users.
+/// Without an argument, this is the sender of the replied-to message, or else the current group.
+fn parse_target(
+    arg: &str,
+    reply_to_user: Option<UserId>,
+    chat: &teloxide::types::Chat,
+) -> Option<Target> {
+    match arg.trim() {
+        "" => reply_to_user
+            .map(Target::User)
+            .or_else(|| (!chat.is_private()).then_some(Target::Chat(chat.id))),
+        arg => {
+            let id = arg.parse::<i64>().ok()?;
+            Some(if id < 0 {
+                Target::Chat(ChatId(id))
+            } else {
+                Target::User(UserId(id.unsigned_abs()))
+            })
+        }
+    }
+}
+
+/// Handles the owner's /allow, /block and /invite commands
+pub fn access_command(
+    cmd: &Command,
+    access: &mut AccessList,
+    reply_to_user: Option<UserId>,
+    chat: &teloxide::types::Chat,
+    bot_username: &str,
+) -> String {
+    match cmd {
+        Command::Allow(arg) | Command::Block(arg) => {
+            let Some(target) = parse_target(arg, reply_to_user, chat) else {
+                return "Use this with a user or chat ID, in reply to someone's message, or in a group to apply it to the group.".into();
+            };
+            let allow = matches!(cmd, Command::Allow(_));
+            match (target, allow) {
+                (Target::User(id), true) => {
+                    access.blocked_users.remove(&id);
+                    access.allowed_users.insert(id);
+                }
+                (Target::User(id), false) => {
+                    access.allowed_users.remove(&id);
+                    access.blocked_users.insert(id);
+                }
+                (Target::Chat(id), true) => {
+                    access.blocked_chats.remove(&id);
+                    access.allowed_chats.insert(id);
+                }
+                (Target::Chat(id), false) => {
+                    access.allowed_chats.remove(&id);
+                    access.blocked_chats.insert(id);
+                }
+            }
+            let what = match target {
+                Target::User(id) => format!("user {id}"),
+                Target::Chat(id) => format!("chat {id}"),
+            };
+            if allow {
+                format!("Allowed {what}.")
+            } else {
+                format!("Blocked {what}.")
+            }
+        }
+        Command::Invite(uses) => {
+            let code = access.new_invite(*uses);
+            format!(
+                "Invite link for {} user{}:\nhttps://t.me/{bot_username}?start={code}",
+                uses.max(&1),
+                if *uses > 1 { "s" } else { "" }
+            )
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invites_are_used_up() {
+        let mut access = AccessList::default();
+        let code = access.new_invite(2);
+        assert!(access.redeem_invite(&code, UserId(1)));
+        assert_eq!(access.invite_codes[&code], 1);
+        assert!(access.redeem_invite(&code, UserId(2)));
+        assert!(!access.invite_codes.contains_key(&code));
+        assert!(!access.redeem_invite(&code, UserId(3)));
+        assert!(!access.allowed_users.contains(&UserId(3)));
+    }
+
+    #[test]
+    fn invites_without_uses_left_are_refused() {
+        let mut access = AccessList::default();
+        access.invite_codes.insert("abcdefghijkl".into(), 0);
+        assert!(!access.redeem_invite("abcdefghijkl", UserId(1)));
+        assert!(!access.allowed_users.contains(&UserId(1)));
+    }
+
+    #[test]
+    fn blocked_users_cant_use_invites() {
+        let mut access = AccessList::default();
+        let code = access.new_invite(1);
+        access.blocked_users.insert(UserId(1));
+        assert!(!access.redeem_invite(&code, UserId(1)));
+        assert!(access.blocked_users.contains(&UserId(1)));
+        assert_eq!(access.invite_codes[&code], 1);
+    }
+}
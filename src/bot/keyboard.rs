@@ -0,0 +1,125 @@
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, UserId};
+
+use crate::models::Branches;
+
+/// Inline buttons shown under the bot's latest reply
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyAction {
+    PrevBranch,
+    NextBranch,
+    Regenerate,
+    Continue,
+    Shorter,
+    Longer,
+    CopyAsCode,
+}
+
+impl ReplyAction {
+    const ALL: [Self; 7] = [
+        Self::PrevBranch,
+        Self::NextBranch,
+        Self::Regenerate,
+        Self::Continue,
+        Self::Shorter,
+        Self::Longer,
+        Self::CopyAsCode,
+    ];
+
+    fn data(self) -> &'static str {
+        match self {
+            Self::PrevBranch => "branch_prev",
+            Self::NextBranch => "branch_next",
+            Self::Regenerate => "regenerate",
+            Self::Continue => "continue",
+            Self::Shorter => "shorter",
+            Self::Longer => "longer",
+            Self::CopyAsCode => "copy",
+        }
+    }
+    fn label(self) -> &'static str {
+        match self {
+            Self::PrevBranch => "◀",
+            Self::NextBranch => "▶",
+            Self::Regenerate => "🔄 Regenerate",
+            Self::Continue => "➡️ Continue",
+            Self::Shorter => "Shorter",
+            Self::Longer => "Longer",
+            Self::CopyAsCode => "Copy as code",
+        }
+    }
+    pub fn parse(data: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|action| action.data() == data)
+    }
+    fn button(self) -> InlineKeyboardButton {
+        InlineKeyboardButton::callback(self.label(), self.data())
+    }
+
+    /// The model instruction for actions that rewrite or extend the reply
+    pub fn instruction(self) -> Option<&'static str> {
+        match self {
+            Self::Continue => Some("Continue your last message from exactly where it stopped. Do not repeat any of it, and do not acknowledge this instruction."),
+            Self::Shorter => Some("Rewrite your last message to be noticeably shorter, keeping the important parts. Reply only with the rewritten message."),
+            Self::Longer => Some("Rewrite your last message to be longer and more detailed. Reply only with the rewritten message."),
+            _ => None,
+        }
+    }
+}
+
+/// Buttons for the bot's latest reply, with "◀ 2/3 ▶" for flipping between branches if there is
+/// more than one
+pub fn reply_keyboard(branches: &Branches) -> InlineKeyboardMarkup {
+    let mut rows = vec![];
+    if !branches.is_empty() {
+        let (current, total) = branches.position();
+        rows.push(vec![
+            ReplyAction::PrevBranch.button(),
+            InlineKeyboardButton::callback(format!("{current}/{total}"), "noop"),
+            ReplyAction::NextBranch.button(),
+        ]);
+    }
+    rows.push(vec![
+        ReplyAction::Regenerate.button(),
+        ReplyAction::Continue.button(),
+    ]);
+    rows.push(vec![
+        ReplyAction::Shorter.button(),
+        ReplyAction::Longer.button(),
+        ReplyAction::CopyAsCode.button(),
+    ]);
+    InlineKeyboardMarkup::new(rows)
+}
+
+/// The buttons under the /forgetme confirmation. They carry the ID of the user who asked, so
+/// nobody else can press them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForgetMe {
+    Confirm(UserId),
+    Cancel(UserId),
+}
+
+impl ForgetMe {
+    pub fn parse(data: &str) -> Option<Self> {
+        let (action, user_id) = data.strip_prefix("forgetme_")?.split_once(':')?;
+        let user_id = UserId(user_id.parse().ok()?);
+        match action {
+            "confirm" => Some(Self::Confirm(user_id)),
+            "cancel" => Some(Self::Cancel(user_id)),
+            _ => None,
+        }
+    }
+    pub fn user_id(self) -> UserId {
+        match self {
+            Self::Confirm(user_id) | Self::Cancel(user_id) => user_id,
+        }
+    }
+}
+
+pub fn forget_me_keyboard(user_id: UserId) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[
+        InlineKeyboardButton::callback(
+            "🗑 Yes, delete everything",
+            format!("forgetme_confirm:{}", user_id.0),
+        ),
+        InlineKeyboardButton::callback("Cancel", format!("forgetme_cancel:{}", user_id.0)),
+    ]])
+}
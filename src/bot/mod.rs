@@ -1,6 +1,15 @@
 use anyhow::Result;
 
-use crate::models::{Conversation, Role, UserState};
+use teloxide::types::{BotCommand, MessageId};
+use teloxide::utils::command::{BotCommands, ParseError};
+
+pub mod access;
+pub mod keyboard;
+pub mod permissions;
+pub mod ratelimit;
+
+use crate::models::export::Export;
+use crate::models::{Branches, Conversation, Role, UserMemory, UserState, MAX_MEMORIES};
 
 // command => requirements
 // start => state, models? Tg bot for keyboard
@@ -19,36 +28,292 @@ use crate::models::{Conversation, Role, UserState};
 // EVERYTHING NEEDED
 // tg bot, state(conversation), models??, ai bot??
 
-// TODO: fit this into the commands, rather than having to maintain a separate list
-const COMMANDS: &[(&str, &str)] = &[
-    ("reset", "Resets the conversation and system message"),
-    ("redo", "Forces the bot to re-type the last message"),
-    ("system", "Set the system message for current conversation"),
-    //("start", "Start a new conversation. Requires model name."),
-    ("help", "Show a list of commands and brief descriptions"),
-    //("rename", "Rename conversation"),
-    //("desc", "Update description of conversation"),
-    //("new", "Start a new conversation"),
-    //("list", "List all conversations"),
+#[derive(BotCommands, Clone, Debug, PartialEq, Eq)]
+#[command(
+    rename_rule = "lowercase",
+    description = "These commands are supported:"
+)]
+pub enum Command {
+    #[command(description = "Start chatting, optionally with an invite code")]
+    Start(String),
+    #[command(description = "Show a list of commands and brief descriptions")]
+    Help,
+    #[command(description = "Resets the conversation and system message")]
+    Reset,
+    #[command(description = "Forces the bot to re-type the last message")]
+    Redo,
+    #[command(description = "Set the system message for current conversation")]
+    System(String),
+    #[command(description = "Copy the current conversation into a new one")]
+    Fork(String),
+    #[command(description = "Remove your last message and the bot's reply")]
+    Undo,
+    #[command(
+        description = "Remove the last n messages (default 1)",
+        parse_with = parse_count
+    )]
+    Pop(usize),
+    #[command(description = "Reply to a message with this to remove it from the conversation")]
+    Delete,
+    #[command(description = "Remember a fact about you across conversations")]
+    Remember(String),
+    #[command(description = "List everything the bot remembers about you")]
+    Memories,
+    #[command(
+        description = "Forget a remembered fact by number, or `all`",
+        parse_with = parse_forget
+    )]
+    Forget(ForgetTarget),
+    #[command(description = "Show which commands only group admins can use")]
+    Permissions,
+    #[command(description = "Only let group admins use a command")]
+    Restrict(String),
+    #[command(description = "Let everyone in the group use a command")]
+    Unrestrict(String),
+    #[command(
+        description = "Wait this many seconds for more messages before replying, or `off`",
+        parse_with = parse_debounce
+    )]
+    Debounce(DebounceSetting),
+    #[command(description = "Show how much you and this chat have used the bot")]
+    Usage,
+    #[command(description = "Show how long your conversations are kept")]
+    Retention,
+    #[command(description = "Get this chat's conversations as JSON and Markdown files")]
+    Export,
+    #[command(description = "Delete everything the bot has stored about you")]
+    ForgetMe,
+    #[command(description = "Import conversations from a JSON file")]
+    Import,
+    //#[command(description = "Rename conversation")]
+    //Rename(String),
+    //#[command(description = "Update description of conversation")]
+    //Desc,
+    //#[command(description = "Start a new conversation")]
+    //New,
+    //#[command(description = "List all conversations")]
+    //List,
+    #[command(description = "off")]
+    Debug,
+    #[command(description = "off")]
+    Allow(String),
+    #[command(description = "off")]
+    Block(String),
+    #[command(description = "off", parse_with = parse_count)]
+    Invite(usize),
+}
+
+impl Command {
+    /// Name as typed by users, without the leading slash
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Start(_) => "start",
+            Command::Help => "help",
+            Command::Reset => "reset",
+            Command::Redo => "redo",
+            Command::System(_) => "system",
+            Command::Fork(_) => "fork",
+            Command::Undo => "undo",
+            Command::Pop(_) => "pop",
+            Command::Delete => "delete",
+            Command::Remember(_) => "remember",
+            Command::Memories => "memories",
+            Command::Forget(_) => "forget",
+            Command::Permissions => "permissions",
+            Command::Restrict(_) => "restrict",
+            Command::Unrestrict(_) => "unrestrict",
+            Command::Debounce(_) => "debounce",
+            Command::Usage => "usage",
+            Command::Retention => "retention",
+            Command::Export => "export",
+            Command::ForgetMe => "forgetme",
+            Command::Import => "import",
+            Command::Debug => "debug",
+            Command::Allow(_) => "allow",
+            Command::Block(_) => "block",
+            Command::Invite(_) => "invite",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForgetTarget {
+    All,
+    /// 1-based, as shown by /memories
+    Fact(usize),
+}
+
+/// Alternative names for commands, mapped to their canonical name
+const ALIASES: &[(&str, &str)] = &[
+    ("clear", "reset"),
+    ("regenerate", "redo"),
+    ("retry", "redo"),
+    ("commands", "help"),
 ];
 
+/// Commands that list or change the sender's personal memories only work in private chats, and
+/// aren't advertised in groups, as everyone would see the replies
+const PRIVATE_ONLY: &[&str] = &["remember", "memories", "forget"];
+/// Group permission settings don't do anything in private chats
+const GROUP_ONLY: &[&str] = &["permissions", "restrict", "unrestrict"];
+
+// teloxide requires custom parsers to take a `String`
+#[allow(clippy::needless_pass_by_value)]
+fn parse_count(input: String) -> Result<(usize,), ParseError> {
+    if input.trim().is_empty() {
+        return Ok((1,));
+    }
+    input
+        .trim()
+        .parse()
+        .map(|n| (n,))
+        .map_err(|e| ParseError::IncorrectFormat(Box::new(e)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebounceSetting {
+    Show,
+    Seconds(u64),
+}
+
+/// Longest /debounce users can set, longer feels like the bot is broken
+const MAX_DEBOUNCE_SECONDS: u64 = 30;
+
+#[allow(clippy::needless_pass_by_value)]
+fn parse_debounce(input: String) -> Result<(DebounceSetting,), ParseError> {
+    match input.trim() {
+        "" => Ok((DebounceSetting::Show,)),
+        "off" => Ok((DebounceSetting::Seconds(0),)),
+        n => n
+            .parse()
+            .map(|n| (DebounceSetting::Seconds(n),))
+            .map_err(|e| ParseError::IncorrectFormat(Box::new(e))),
+    }
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn parse_forget(input: String) -> Result<(ForgetTarget,), ParseError> {
+    match input.trim() {
+        "all" => Ok((ForgetTarget::All,)),
+        n => n
+            .parse()
+            .map(|n| (ForgetTarget::Fact(n),))
+            .map_err(|e| ParseError::IncorrectFormat(Box::new(e))),
+    }
+}
+
+/// Whether the chat is shared with other people. `Chat::is_group` only matches basic groups, but
+/// most groups are supergroups.
+pub fn is_group_chat(chat: &teloxide::types::Chat) -> bool {
+    !chat.is_private()
+}
+
+/// Parses a command, resolving aliases. Returns `None` for commands addressed to other bots
+/// (`/command@OtherBot`), which should be ignored. Errors are messages to show the user.
+pub fn parse_command(text: &str, bot_username: &str) -> Result<Option<Command>, String> {
+    let (name, rest) = text.split_once(' ').unwrap_or((text, ""));
+    let (name, addressee) = name
+        .split_once('@')
+        .map_or((name, None), |(n, a)| (n, Some(a)));
+    let name = name.trim_start_matches('/');
+    let name = ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+        .map_or(name, |(_, canonical)| canonical);
+    let text = match addressee {
+        Some(addressee) => format!("/{name}@{addressee} {rest}"),
+        None => format!("/{name} {rest}"),
+    };
+    match Command::parse(text.trim_end(), bot_username) {
+        Ok(command) => Ok(Some(command)),
+        Err(ParseError::WrongBotName(_)) => Ok(None),
+        Err(e) => Err(parse_error_message(e, name)),
+    }
+}
+
+fn parse_error_message(e: ParseError, name: &str) -> String {
+    match e {
+        ParseError::UnknownCommand(cmd) => format!("Unknown command {cmd}. See /help."),
+        ParseError::IncorrectFormat(_)
+        | ParseError::TooFewArguments { .. }
+        | ParseError::TooManyArguments { .. } => match name {
+            "pop" => "Use `/pop [number of messages]`.".into(),
+            "forget" => {
+                "Use `/forget [number]` with a number from /memories, or `/forget all`.".into()
+            }
+            _ => format!("Wrong arguments for /{name}. See /help."),
+        },
+        e => format!("Couldn't parse command: {e}"),
+    }
+}
+
+pub fn help_text() -> String {
+    let aliases = ALIASES
+        .iter()
+        .map(|(alias, canonical)| format!("/{alias} → /{canonical}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("{}\n\nAliases:\n{aliases}", Command::descriptions())
+}
+
+/// Visible commands, named without the leading slash
+pub fn menu_commands() -> Vec<BotCommand> {
+    Command::bot_commands()
+        .into_iter()
+        .map(|cmd| BotCommand::new(cmd.command.trim_start_matches('/'), cmd.description))
+        .collect()
+}
+
+/// Commands to register in Telegram's command menu for private chats
+pub fn private_commands() -> Vec<BotCommand> {
+    menu_commands()
+        .into_iter()
+        .filter(|cmd| !GROUP_ONLY.contains(&cmd.command.as_str()))
+        .collect()
+}
+
+/// Commands to register in Telegram's command menu for group chats
+pub fn group_commands() -> Vec<BotCommand> {
+    menu_commands()
+        .into_iter()
+        .filter(|cmd| !PRIVATE_ONLY.contains(&cmd.command.as_str()))
+        .collect()
+}
+
 pub enum CommandResult<'a> {
     //DoNothing,
-    RegenerateLastMessage(&'a mut Conversation),
+    /// The old reply has been moved into a sibling branch, which the new reply should hold
+    RegenerateLastMessage(&'a mut Conversation, Branches),
     ReplyToUser(String),
+    /// Reply, then delete the bot's Telegram messages that were removed from the conversation
+    DeleteMessages(String, Vec<MessageId>),
+    /// Send these files, by name
+    SendFiles(Vec<(String, Vec<u8>)>),
+    /// Ask the user to confirm deleting all of their data
+    ConfirmForgetMe,
     //GenerateDescription(&'a mut Conversation),
 }
 
-// Does not handle /start
-pub fn handle_command<'a>(msg: &str, state: &'a mut UserState) -> Result<CommandResult<'a>> {
-    let (cmd, rest) = msg.split_once(' ').unwrap_or((msg, ""));
+// Does not handle /allow, /block and /invite (see `access::access_command`), or /usage.
+// `memory` is the sender's, only given in private chats.
+pub fn handle_command<'a>(
+    cmd: Command,
+    reply_to: Option<MessageId>,
+    group_chat: bool,
+    state: &'a mut UserState,
+    memory: Option<&mut UserMemory>,
+) -> Result<CommandResult<'a>> {
     // Only work in conversation
-    let failed_command = Ok(CommandResult::ReplyToUser(format!(
-        "Command `{cmd}` requires you to be in a conversation!"
-    )));
+    let failed_command = Ok(CommandResult::ReplyToUser(
+        "This command requires you to be in a conversation!".into(),
+    ));
     match cmd {
-        "/debug" => Ok(CommandResult::ReplyToUser(format!("state: {state:#?}"))),
-        "/reset" => {
+        Command::Start(_) => Ok(CommandResult::ReplyToUser(
+            "Hi! Just send me a message to start chatting. See /help for commands.".into(),
+        )),
+        Command::Help => Ok(CommandResult::ReplyToUser(help_text())),
+        Command::Debug => Ok(CommandResult::ReplyToUser(format!("state: {state:#?}"))),
+        Command::Reset => {
             let Some(conversation) = state.get_current_conversation() else {
                 return failed_command;
             };
@@ -56,26 +321,26 @@ pub fn handle_command<'a>(msg: &str, state: &'a mut UserState) -> Result<Command
             conversation.system = None;
             Ok(CommandResult::ReplyToUser("Conversation reset!".into()))
         }
-        //"/rename" => {
+        //Command::Rename(name) => {
         //    let conversation = state.get_or_create_conversation();
-        //    rest.clone_into(&mut conversation.name);
+        //    name.clone_into(&mut conversation.name);
         //    Ok(CommandResult::ReplyToUser(format!(
-        //        "Set current conversation name to \"{rest}\"!"
+        //        "Set current conversation name to \"{name}\"!"
         //    )))
         //}
-        //"/desc" => {
+        //Command::Desc => {
         //    let Some(conversation) = state.get_current_conversation() else {
         //        return failed_command;
         //    };
         //    Ok(CommandResult::GenerateDescription(conversation))
         //}
-        //"/new" => {
+        //Command::New => {
         //    state.current_conversation = None;
         //    Ok(CommandResult::ReplyToUser(
         //        "New conversation started".into(),
         //    ))
         //}
-        //"/list" => {
+        //Command::List => {
         //    let conversations = state
         //        .conversations
         //        .iter()
@@ -87,35 +352,404 @@ pub fn handle_command<'a>(msg: &str, state: &'a mut UserState) -> Result<Command
         //        "Current conversations:\n{conversations}"
         //    )))
         //}
-        "/system" => {
+        Command::System(system) => {
             let Some(conversation) = state.get_current_conversation() else {
                 return failed_command;
             };
-            if rest.is_empty() {
+            if system.is_empty() {
                 return Ok(CommandResult::ReplyToUser(
                     "Please set a system message with `/system [system message]`.".into(),
                 ));
             }
-            conversation.system = Some(rest.into());
+            conversation.system = Some(system);
             Ok(CommandResult::ReplyToUser("System message set!".into()))
         }
-        "/redo" => {
+        Command::Redo => {
             let Some(conversation) = state.get_current_conversation() else {
                 return failed_command;
             };
             if conversation
                 .messages
                 .last()
-                .map_or(false, |m| m.from != Role::Assistant)
+                .is_some_and(|m| m.from != Role::Assistant)
             {
                 return Ok(CommandResult::ReplyToUser(
                     "Can only /redo if the last message is LlamaBot's!".into(),
                 ));
+            }
+            let branches = conversation.branch_from(conversation.messages.len().saturating_sub(1));
+            Ok(CommandResult::RegenerateLastMessage(conversation, branches))
+        }
+        Command::Fork(name) => {
+            let Some(conversation) = state.get_current_conversation() else {
+                return failed_command;
+            };
+            let name = if name.is_empty() {
+                format!("Fork of {}", conversation.name)
+            } else {
+                name
             };
-            Ok(CommandResult::RegenerateLastMessage(conversation))
+            let fork = conversation.fork(name.clone());
+            state.conversations.push(fork);
+            state.current_conversation = Some(state.conversations.len() - 1);
+            Ok(CommandResult::ReplyToUser(format!(
+                "Forked into new conversation \"{name}\"!"
+            )))
         }
-        _ => Ok(CommandResult::ReplyToUser(format!(
-            "Unknown command {cmd}."
+        Command::Undo | Command::Pop(_) | Command::Delete => {
+            let current = state.current_conversation;
+            let target = reply_to
+                .and_then(|id| state.find_message(id))
+                .filter(|&(conv_idx, _)| Some(conv_idx) == current)
+                .map(|(_, msg_idx)| msg_idx);
+            let Some(conversation) = state.get_current_conversation() else {
+                return failed_command;
+            };
+            if cmd == Command::Delete && reply_to.is_none() {
+                return Ok(CommandResult::ReplyToUser(
+                    "Reply to the message you want to delete with /delete.".into(),
+                ));
+            }
+            Ok(history_command(&cmd, target, conversation))
+        }
+        Command::Permissions | Command::Restrict(_) | Command::Unrestrict(_) => {
+            Ok(CommandResult::ReplyToUser(if group_chat {
+                permissions_command(&cmd, state)
+            } else {
+                "Permissions can only be configured in groups.".into()
+            }))
+        }
+        Command::Debounce(setting) => Ok(CommandResult::ReplyToUser(debounce_command(
+            setting, group_chat, state,
         ))),
+        Command::Export => export_command(state),
+        Command::ForgetMe => Ok(CommandResult::ConfirmForgetMe),
+        Command::Import => Ok(CommandResult::ReplyToUser(import_help(group_chat))),
+        Command::Remember(_) | Command::Memories | Command::Forget(_) => {
+            Ok(CommandResult::ReplyToUser(memory_command(cmd, memory)))
+        }
+        Command::Allow(_) | Command::Block(_) | Command::Invite(_) => {
+            unreachable!("access commands are handled by access::access_command")
+        }
+        Command::Usage | Command::Retention => {
+            unreachable!("usage and retention are reported from the bot context")
+        }
+    }
+}
+
+/// `target` is the index of the message replied to, if it is in the current conversation
+fn history_command(
+    cmd: &Command,
+    target: Option<usize>,
+    conversation: &mut Conversation,
+) -> CommandResult<'static> {
+    let range = match cmd {
+        Command::Undo => {
+            let Some(start) = conversation.last_turn_start() else {
+                return CommandResult::ReplyToUser("Nothing to undo!".into());
+            };
+            start..conversation.messages.len()
+        }
+        &Command::Pop(n) => {
+            let len = conversation.messages.len();
+            len.saturating_sub(n)..len
+        }
+        Command::Delete => {
+            let Some(idx) = target else {
+                return CommandResult::ReplyToUser(
+                    "That message isn't part of the current conversation.".into(),
+                );
+            };
+            conversation.turn_at(idx)
+        }
+        _ => unreachable!(),
+    };
+    let count = range.len();
+    let deleted = conversation.remove_messages(range);
+    CommandResult::DeleteMessages(
+        format!(
+            "Removed {count} message{} from the conversation.",
+            if count == 1 { "" } else { "s" }
+        ),
+        deleted,
+    )
+}
+
+fn import_help(group_chat: bool) -> String {
+    let how = if group_chat {
+        "Send a JSON file with /import as its caption to import it as a new conversation."
+    } else {
+        "Send me a JSON file to import it as a new conversation."
+    };
+    format!("{how} It can be a file from /export, or an OpenAI-style `messages` array.")
+}
+
+fn export_command(state: &UserState) -> Result<CommandResult<'static>> {
+    if state.conversations.iter().all(|c| c.messages.is_empty()) {
+        return Ok(CommandResult::ReplyToUser(
+            "There's nothing to export yet.".into(),
+        ));
+    }
+    let export = Export::new(state);
+    Ok(CommandResult::SendFiles(vec![
+        ("conversations.json".into(), export.to_json()?),
+        ("conversations.md".into(), export.to_markdown().into_bytes()),
+    ]))
+}
+
+fn permissions_command(cmd: &Command, state: &mut UserState) -> String {
+    let mut restricted = permissions::restricted_commands(state);
+    match cmd {
+        Command::Permissions => {
+            if restricted.is_empty() {
+                return "Everyone can use all commands here.".into();
+            }
+            let list = restricted
+                .iter()
+                .map(|name| format!("/{name}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("Only group admins can use: {list}")
+        }
+        Command::Restrict(name) | Command::Unrestrict(name) => {
+            let restrict = matches!(cmd, Command::Restrict(_));
+            let name = name.trim().trim_start_matches('/').to_lowercase();
+            if !permissions::is_configurable(&name) {
+                return format!("/{name} isn't a command that can be restricted.");
+            }
+            restricted.retain(|n| *n != name);
+            if restrict {
+                restricted.push(name.clone());
+            }
+            state.restricted_commands = Some(restricted);
+            if restrict {
+                format!("Only group admins can use /{name} now.")
+            } else {
+                format!("Everyone can use /{name} now.")
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn debounce_command(setting: DebounceSetting, group_chat: bool, state: &mut UserState) -> String {
+    match setting {
+        DebounceSetting::Show => match state.debounce(group_chat).as_secs() {
+            0 => "Replying to every message right away. Use /debounce <seconds> to wait for more messages first.".into(),
+            secs => format!("Waiting {secs} seconds after your last message before replying. Use /debounce off to reply right away."),
+        },
+        DebounceSetting::Seconds(secs) if secs > MAX_DEBOUNCE_SECONDS => {
+            format!("That's too long, the most is {MAX_DEBOUNCE_SECONDS} seconds.")
+        }
+        DebounceSetting::Seconds(0) => {
+            state.debounce_seconds = Some(0);
+            "Replying to every message right away now.".into()
+        }
+        DebounceSetting::Seconds(secs) => {
+            state.debounce_seconds = Some(secs);
+            format!("Waiting {secs} seconds for more messages before replying now.")
+        }
+    }
+}
+
+fn memory_command(cmd: Command, memory: Option<&mut UserMemory>) -> String {
+    let Some(memory) = memory else {
+        return "Memories are private, manage them in a private chat with me.".into();
+    };
+    match cmd {
+        Command::Remember(fact) => {
+            if fact.is_empty() {
+                return "Tell me what to remember with `/remember [fact]`.".into();
+            }
+            if memory.facts.len() >= MAX_MEMORIES {
+                return format!(
+                    "I can only remember {MAX_MEMORIES} things about you, `/forget` some first!"
+                );
+            }
+            if !memory.remember(&fact) {
+                return "I already knew that!".into();
+            }
+            "Got it, I'll remember that!".into()
+        }
+        Command::Memories => {
+            if memory.facts.is_empty() {
+                return "I don't remember anything about you yet.".into();
+            }
+            let facts = memory
+                .facts
+                .iter()
+                .enumerate()
+                .map(|(i, f)| format!("{}: {f}", i + 1))
+                .collect::<Vec<_>>()
+                .join("\n");
+            format!("Things I remember about you:\n{facts}")
+        }
+        Command::Forget(ForgetTarget::All) => {
+            memory.facts.clear();
+            "Forgot everything about you!".into()
+        }
+        Command::Forget(ForgetTarget::Fact(idx)) => {
+            if !(1..=memory.facts.len()).contains(&idx) {
+                return "Use `/forget [number]` with a number from /memories, or `/forget all`."
+                    .into();
+            }
+            let fact = memory.facts.remove(idx - 1);
+            format!("Forgot \"{fact}\"!")
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::testing::{chat, current, exchange};
+
+    fn run(cmd: Command, reply_to: Option<i32>, state: &mut UserState) -> (String, Vec<MessageId>) {
+        let mut memory = UserMemory::default();
+        match handle_command(
+            cmd,
+            reply_to.map(MessageId),
+            false,
+            state,
+            Some(&mut memory),
+        )
+        .unwrap()
+        {
+            CommandResult::DeleteMessages(reply, deleted) => (reply, deleted),
+            CommandResult::ReplyToUser(reply) => (reply, vec![]),
+            _ => panic!("unexpected command result"),
+        }
+    }
+
+    #[test]
+    fn delete_on_a_user_turn_removes_the_replies() {
+        let mut state = chat(exchange(&[
+            "hi",
+            "hello",
+            "how are you?",
+            "fine",
+            "bye",
+            "see you",
+        ]));
+        let (reply, deleted) = run(Command::Delete, Some(3), &mut state);
+        assert_eq!(reply, "Removed 2 messages from the conversation.");
+        assert_eq!(deleted, [MessageId(4)]);
+        assert_eq!(current(&state), ["hi", "hello", "bye", "see you"]);
+    }
+
+    #[test]
+    fn delete_on_a_reply_removes_only_it() {
+        let mut state = chat(exchange(&["hi", "hello", "bye", "see you"]));
+        let (reply, deleted) = run(Command::Delete, Some(2), &mut state);
+        assert_eq!(reply, "Removed 1 message from the conversation.");
+        assert_eq!(deleted, [MessageId(2)]);
+        assert_eq!(current(&state), ["hi", "bye", "see you"]);
+    }
+
+    #[test]
+    fn delete_needs_a_message_in_the_conversation() {
+        let mut state = chat(exchange(&["hi", "hello"]));
+        let (reply, _) = run(Command::Delete, None, &mut state);
+        assert_eq!(
+            reply,
+            "Reply to the message you want to delete with /delete."
+        );
+        let (reply, _) = run(Command::Delete, Some(42), &mut state);
+        assert_eq!(
+            reply,
+            "That message isn't part of the current conversation."
+        );
+        assert_eq!(current(&state), ["hi", "hello"]);
+    }
+
+    #[test]
+    fn pop_more_than_the_history_empties_it() {
+        let mut state = chat(exchange(&["hi", "hello", "bye"]));
+        let (reply, deleted) = run(Command::Pop(10), None, &mut state);
+        assert_eq!(reply, "Removed 3 messages from the conversation.");
+        assert_eq!(deleted, [MessageId(2)]);
+        assert!(current(&state).is_empty());
+        let (reply, _) = run(Command::Pop(1), None, &mut state);
+        assert_eq!(reply, "Removed 0 messages from the conversation.");
+    }
+
+    #[test]
+    fn undo_removes_the_last_exchange() {
+        let mut state = chat(exchange(&["hi", "hello", "bye", "see you"]));
+        let (_, deleted) = run(Command::Undo, None, &mut state);
+        assert_eq!(deleted, [MessageId(4)]);
+        assert_eq!(current(&state), ["hi", "hello"]);
+        run(Command::Undo, None, &mut state);
+        let (reply, _) = run(Command::Undo, None, &mut state);
+        assert_eq!(reply, "Nothing to undo!");
+    }
+
+    #[test]
+    fn aliases_resolve_to_commands() {
+        assert_eq!(parse_command("/clear", "bot"), Ok(Some(Command::Reset)));
+        assert_eq!(parse_command("/pop", "bot"), Ok(Some(Command::Pop(1))));
+        assert_eq!(parse_command("/pop 3", "bot"), Ok(Some(Command::Pop(3))));
+        assert_eq!(parse_command("/pop@other_bot", "bot"), Ok(None));
+    }
+
+    fn telegram_chat(json: serde_json::Value) -> teloxide::types::Chat {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn supergroups_are_restricted_like_groups() {
+        let supergroup = telegram_chat(
+            serde_json::json!({"id": -1_001_234_567_890_i64, "type": "supergroup", "title": "Friends"}),
+        );
+        let group =
+            telegram_chat(serde_json::json!({"id": -1234, "type": "group", "title": "Friends"}));
+        let private =
+            telegram_chat(serde_json::json!({"id": 42, "type": "private", "first_name": "Alex"}));
+        assert!(!supergroup.is_group());
+        assert!(is_group_chat(&supergroup));
+        assert!(is_group_chat(&group));
+        assert!(!is_group_chat(&private));
+
+        let state = UserState::default();
+        let access =
+            |chat| permissions::required_access(&Command::Reset, is_group_chat(chat), &state);
+        assert_eq!(access(&supergroup), permissions::Access::ChatAdmin);
+        assert_eq!(access(&private), permissions::Access::Anyone);
+
+        let CommandResult::ReplyToUser(reply) = handle_command(
+            Command::Permissions,
+            None,
+            is_group_chat(&supergroup),
+            &mut UserState::default(),
+            None,
+        )
+        .unwrap() else {
+            panic!("unexpected command result");
+        };
+        assert!(!reply.contains("only be configured in groups"), "{reply}");
+    }
+
+    #[test]
+    fn memories_stay_out_of_shared_chats() {
+        let mut memory = UserMemory::default();
+        memory.remember("Lives in Lisbon");
+        let memories = |memory| {
+            let Ok(CommandResult::ReplyToUser(reply)) = handle_command(
+                Command::Memories,
+                None,
+                true,
+                &mut UserState::default(),
+                memory,
+            ) else {
+                panic!("unexpected command result");
+            };
+            reply
+        };
+        assert!(memories(Some(&mut memory)).contains("Lives in Lisbon"));
+        assert_eq!(
+            memories(None),
+            "Memories are private, manage them in a private chat with me."
+        );
     }
 }
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use teloxide::prelude::*;
+
+use super::Command;
+use crate::models::UserState;
+
+/// How long a user's admin status is trusted before asking Telegram again
+const ADMIN_CACHE_TTL: Duration = Duration::from_mins(5);
+
+/// Who may use a command. Ordered from least to most privileged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Anyone,
+    ChatAdmin,
+    BotOwner,
+}
+
+impl Access {
+    pub fn describe(self) -> &'static str {
+        match self {
+            Access::Anyone => "anyone",
+            Access::ChatAdmin => "group admins",
+            Access::BotOwner => "the bot owner",
+        }
+    }
+}
+
+/// Access required for a command regardless of group settings, if any
+fn fixed_access(name: &str) -> Option<Access> {
+    match name {
+        "debug" | "allow" | "block" | "invite" => Some(Access::BotOwner),
+        "restrict" | "unrestrict" => Some(Access::ChatAdmin),
+        "help" | "start" | "permissions" | "usage" | "retention" | "forgetme" => {
+            Some(Access::Anyone)
+        }
+        _ => None,
+    }
+}
+
+/// Commands restricted to admins in groups that haven't configured anything
+const DEFAULT_RESTRICTED: &[&str] = &["reset", "system"];
+
+/// Whether a command can be restricted to admins with /restrict
+pub fn is_configurable(name: &str) -> bool {
+    fixed_access(name).is_none() && super::menu_commands().iter().any(|cmd| cmd.command == name)
+}
+
+/// The group's admin-only commands
+pub fn restricted_commands(state: &UserState) -> Vec<String> {
+    state.restricted_commands.clone().unwrap_or_else(|| {
+        DEFAULT_RESTRICTED
+            .iter()
+            .map(|name| (*name).to_string())
+            .collect()
+    })
+}
+
+/// Access needed to run `command` in a chat with the given state
+pub fn required_access(command: &Command, group_chat: bool, state: &UserState) -> Access {
+    if let Some(access) = fixed_access(command.name()) {
+        return access;
+    }
+    if group_chat
+        && restricted_commands(state)
+            .iter()
+            .any(|name| name == command.name())
+    {
+        Access::ChatAdmin
+    } else {
+        Access::Anyone
+    }
+}
+
+pub struct PermissionChecker {
+    owner: Option<UserId>,
+    admins: Mutex<HashMap<(ChatId, UserId), (bool, Instant)>>,
+}
+
+impl PermissionChecker {
+    pub fn new(owner: Option<UserId>) -> Self {
+        Self {
+            owner,
+            admins: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_owner(&self, user_id: UserId) -> bool {
+        self.owner == Some(user_id)
+    }
+
+    /// Whether the user has at least `access` in the chat. In private chats, the user is the
+    /// admin of their own chat.
+    pub async fn allows(
+        &self,
+        bot: &Bot,
+        chat: &teloxide::types::Chat,
+        user_id: UserId,
+        access: Access,
+    ) -> anyhow::Result<bool> {
+        let is_owner = self.owner == Some(user_id);
+        Ok(match access {
+            Access::Anyone => true,
+            Access::BotOwner => is_owner,
+            Access::ChatAdmin => {
+                is_owner || chat.is_private() || self.is_admin(bot, chat.id, user_id).await?
+            }
+        })
+    }
+
+    async fn is_admin(&self, bot: &Bot, chat_id: ChatId, user_id: UserId) -> anyhow::Result<bool> {
+        if let Some(&(is_admin, checked)) = self.admins.lock().unwrap().get(&(chat_id, user_id)) {
+            if checked.elapsed() < ADMIN_CACHE_TTL {
+                return Ok(is_admin);
+            }
+        }
+        let is_admin = bot.get_chat_member(chat_id, user_id).await?.is_privileged();
+        self.admins
+            .lock()
+            .unwrap()
+            .insert((chat_id, user_id), (is_admin, Instant::now()));
+        Ok(is_admin)
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use teloxide::types::{ChatId, UserId};
+
+/// Messages allowed per minute, also the size of a burst. 0 disables the limit.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub per_minute: u32,
+}
+
+impl RateLimit {
+    fn is_disabled(self) -> bool {
+        self.per_minute == 0
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Key {
+    User(UserId),
+    Chat(ChatId),
+}
+
+/// Token bucket, refilled continuously up to the per minute limit
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Whether the user was already told to slow down since running out
+    warned: bool,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            tokens: f64::from(limit.per_minute),
+            updated: Instant::now(),
+            warned: false,
+        }
+    }
+    /// How long until there's a whole token
+    fn wait(&self, limit: RateLimit) -> Duration {
+        Duration::from_secs_f64((1.0 - self.tokens).max(0.0) * 60.0 / f64::from(limit.per_minute))
+    }
+    fn refill(&mut self, limit: RateLimit) {
+        let capacity = f64::from(limit.per_minute);
+        let elapsed = self.updated.elapsed().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * capacity / 60.0).min(capacity);
+        self.updated = Instant::now();
+    }
+}
+
+/// Why the rate limiter said no
+#[derive(Clone, Copy, Debug)]
+pub struct Limited {
+    /// Whether this is the first refusal since running out, so the user should be told
+    pub notify: bool,
+    /// When the next turn can be taken
+    pub retry_after: Duration,
+}
+
+/// Limits how often users, and chats as a whole, can make the bot generate something
+pub struct RateLimiter {
+    /// Per user and per chat
+    limits: Mutex<(RateLimit, RateLimit)>,
+    buckets: Mutex<HashMap<Key, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(user: RateLimit, chat: RateLimit) -> Self {
+        Self {
+            limits: Mutex::new((user, chat)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Changes the limits, keeping everyone's remaining tokens
+    pub fn set_limits(&self, user: RateLimit, chat: RateLimit) {
+        *self.limits.lock().unwrap() = (user, chat);
+    }
+
+    /// Takes a token from both the user's and the chat's bucket. Returns `None` if allowed.
+    pub fn check(&self, user_id: Option<UserId>, chat_id: ChatId) -> Option<Limited> {
+        let (user, chat) = *self.limits.lock().unwrap();
+        let keys = user_id
+            .map(|id| (Key::User(id), user))
+            .into_iter()
+            .chain([(Key::Chat(chat_id), chat)])
+            .filter(|(_, limit)| !limit.is_disabled())
+            .collect::<Vec<_>>();
+        let mut buckets = self.buckets.lock().unwrap();
+        for &(key, limit) in &keys {
+            buckets
+                .entry(key)
+                .or_insert_with(|| Bucket::new(limit))
+                .refill(limit);
+        }
+        let limited = keys.iter().any(|(key, _)| buckets[key].tokens < 1.0);
+        if limited {
+            let mut notify = false;
+            let mut retry_after = Duration::ZERO;
+            for &(key, limit) in &keys {
+                let bucket = buckets.get_mut(&key).unwrap();
+                if bucket.tokens < 1.0 && !bucket.warned {
+                    bucket.warned = true;
+                    notify = true;
+                }
+                retry_after = retry_after.max(bucket.wait(limit));
+            }
+            return Some(Limited {
+                notify,
+                retry_after,
+            });
+        }
+        for (key, _) in &keys {
+            let bucket = buckets.get_mut(key).unwrap();
+            bucket.tokens -= 1.0;
+            bucket.warned = false;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refusals_say_when_to_retry() {
+        let limiter = RateLimiter::new(RateLimit { per_minute: 2 }, RateLimit { per_minute: 0 });
+        let (user, chat) = (Some(UserId(1)), ChatId(1));
+        assert!(limiter.check(user, chat).is_none());
+        assert!(limiter.check(user, chat).is_none());
+
+        let refusal = limiter.check(user, chat).unwrap();
+        assert!(refusal.notify);
+        // A token comes back every 30 seconds
+        assert!(refusal.retry_after > Duration::from_secs(29));
+        assert!(refusal.retry_after <= Duration::from_secs(30));
+        assert!(!limiter.check(user, chat).unwrap().notify);
+
+        // Other users aren't limited, the chat limit is off
+        assert!(limiter.check(Some(UserId(2)), chat).is_none());
+    }
+}
@@ -0,0 +1,643 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use secrecy::SecretString;
+use serde::Deserialize;
+
+use crate::ai::openai::OpenAIModel;
+use crate::models::retention::Retention;
+use crate::models::usage::Quota;
+use crate::models::Backend;
+
+/// Read when no `--config` is given, if it exists
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+const OPENAI_API_URL: &str = "http://localhost:5000/v1";
+const OPENAI_MODEL: &str = "turboderp_Llama-3-70B-Instruct-exl2_5.0bpw";
+const GROQ_API_URL: &str = "https://api.groq.com/openai/v1";
+const GROQ_MODEL: &str = "llama3-70b-8192";
+
+/// Everything that can be set in `config.toml`. See `config.example.toml` for the format.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Name of the backend in `backends` used for replies
+    pub default_backend: String,
+    pub backends: BTreeMap<String, BackendConfig>,
+    /// Directory the chats, memories, access lists and usage are saved in
+    pub storage_path: PathBuf,
+    pub autosave_seconds: u64,
+    /// Timestamped copies of each file kept in `storage_path/backups`, 0 for none
+    pub backups: usize,
+    /// Saves are backed up at most this often
+    pub backup_interval_minutes: u64,
+    /// How long to wait for replies in progress when shutting down
+    pub shutdown_timeout_seconds: u64,
+    pub owner_id: Option<u64>,
+    pub access: AccessConfig,
+    pub prompts: PromptsConfig,
+    pub limits: LimitsConfig,
+    pub logging: LoggingConfig,
+    pub metrics: MetricsConfig,
+    pub webhook: WebhookConfig,
+    pub encryption: EncryptionConfig,
+    pub retention: Retention,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let backends = BTreeMap::from([
+            (
+                "local".into(),
+                BackendConfig {
+                    kind: BackendKind::OpenAI,
+                    url: OPENAI_API_URL.into(),
+                    model: OPENAI_MODEL.into(),
+                    api_key_env: None,
+                },
+            ),
+            (
+                "groq".into(),
+                BackendConfig {
+                    kind: BackendKind::OpenAI,
+                    url: GROQ_API_URL.into(),
+                    model: GROQ_MODEL.into(),
+                    api_key_env: Some("GROQ_TOKEN".into()),
+                },
+            ),
+        ]);
+        // Groq is only usable with a key, the local server is the fallback
+        let default_backend = if std::env::var_os("GROQ_TOKEN").is_some()
+            || std::env::var_os("GROQ_TOKEN_FILE").is_some()
+        {
+            "groq"
+        } else {
+            "local"
+        };
+        Self {
+            default_backend: default_backend.into(),
+            backends,
+            storage_path: ".".into(),
+            autosave_seconds: 300,
+            backups: 24,
+            backup_interval_minutes: 60,
+            shutdown_timeout_seconds: 8,
+            owner_id: None,
+            access: AccessConfig::default(),
+            prompts: PromptsConfig::default(),
+            limits: LimitsConfig::default(),
+            logging: LoggingConfig::default(),
+            metrics: MetricsConfig::default(),
+            webhook: WebhookConfig::default(),
+            encryption: EncryptionConfig::default(),
+            retention: Retention::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    #[serde(rename = "openai")]
+    OpenAI,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BackendConfig {
+    #[serde(rename = "type")]
+    pub kind: BackendKind,
+    pub url: String,
+    pub model: String,
+    /// Environment variable holding the API key, if the backend needs one. The key can also be
+    /// in a file named by the same variable with `_FILE` appended.
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+}
+
+impl BackendConfig {
+    pub fn build(&self, name: &str, default_system: Option<String>) -> anyhow::Result<Backend> {
+        let model = match &self.api_key_env {
+            Some(var) => {
+                let key = secret_from_env(var)?
+                    .with_context(|| format!("Neither {var} nor {var}_FILE is set"))?;
+                OpenAIModel::new_with_token(self.url.clone(), self.model.clone(), &key)
+            }
+            None => OpenAIModel::new(self.url.clone(), self.model.clone()),
+        };
+        Ok(match self.kind {
+            BackendKind::OpenAI => Backend::OpenAI(
+                model
+                    .with_name(name.into())
+                    .with_default_system(default_system),
+            ),
+        })
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// Only serve allowed users and chats. Implied by non-empty allow lists.
+    pub allowlist_only: bool,
+    pub allowed_users: Vec<u64>,
+    pub allowed_chats: Vec<i64>,
+    pub unauthorized_message: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptsConfig {
+    /// System message for conversations that haven't set one with /system
+    pub system: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Replies per minute, 0 for no limit
+    pub user_rate_limit: u32,
+    pub chat_rate_limit: u32,
+    pub user_quota: Quota,
+    pub chat_quota: Quota,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            user_rate_limit: 6,
+            chat_rate_limit: 20,
+            user_quota: Quota::default(),
+            chat_quota: Quota::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `RUST_LOG` style filter, e.g. `info` or `warn,ollama_tg_bot=debug`
+    pub level: String,
+    /// One JSON object per line instead of human readable logs
+    pub json: bool,
+    /// Leave message text out of the logs
+    pub redact_content: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".into(),
+            json: false,
+            redact_content: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Where to serve Prometheus metrics on `/metrics`, e.g. "127.0.0.1:9090". Off if unset.
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Public HTTPS URL Telegram posts updates to. The bot long polls unless this is set.
+    pub url: Option<String>,
+    /// Local address to receive updates on, usually behind a reverse proxy
+    pub listen: SocketAddr,
+    /// Register the URL with Telegram on startup and remove it on shutdown. Turn off to manage
+    /// the webhook yourself, or to post updates to the bot by hand.
+    pub set_webhook: bool,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            listen: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            set_webhook: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// Environment variable with the key the saved files are encrypted with. Off if unset.
+    pub key_env: Option<String>,
+    /// Environment variable with keys the files may still be encrypted with, after rotating
+    pub old_keys_env: Option<String>,
+    /// Read files that aren't encrypted even though there is a key, to encrypt existing data
+    pub allow_plaintext: bool,
+}
+
+impl Config {
+    /// Reads the config file, applies the environment overrides and validates the result
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let config = Self::read(path)?;
+        config.validate().context("Invalid config")?;
+        Ok(config)
+    }
+
+    /// Reads the config file and applies the environment overrides, without checking that the
+    /// bot could run with it. For subcommands that don't run the bot.
+    pub fn read(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = path.or_else(|| {
+            let default = Path::new(DEFAULT_CONFIG_PATH);
+            default.exists().then_some(default)
+        });
+        let mut config = match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("Couldn't read config file {}", path.display()))?;
+                toml::from_str(&contents)
+                    .with_context(|| format!("Invalid config file {}", path.display()))?
+            }
+            None => Self::default(),
+        };
+        config
+            .apply_env()
+            .context("Invalid config in environment")?;
+        Ok(config)
+    }
+
+    /// Environment variables win over the config file
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        env_override("DEFAULT_BACKEND", &mut self.default_backend)?;
+        env_override("STORAGE_PATH", &mut self.storage_path)?;
+        env_override("AUTOSAVE_SECONDS", &mut self.autosave_seconds)?;
+        env_override("BACKUPS", &mut self.backups)?;
+        env_override("BACKUP_INTERVAL_MINUTES", &mut self.backup_interval_minutes)?;
+        env_override(
+            "SHUTDOWN_TIMEOUT_SECONDS",
+            &mut self.shutdown_timeout_seconds,
+        )?;
+        if let Some(owner) = env_value("BOT_OWNER_ID")? {
+            self.owner_id = Some(owner);
+        }
+        if let Some(allowed_users) = env_list("ALLOWED_USERS")? {
+            self.access.allowed_users = allowed_users;
+        }
+        if let Some(allowed_chats) = env_list("ALLOWED_CHATS")? {
+            self.access.allowed_chats = allowed_chats;
+        }
+        env_flag("ALLOWLIST_ONLY", &mut self.access.allowlist_only);
+        if let Ok(message) = std::env::var("UNAUTHORIZED_MESSAGE") {
+            self.access.unauthorized_message = Some(message);
+        }
+        if let Ok(system) = std::env::var("SYSTEM_PROMPT") {
+            self.prompts.system = Some(system);
+        }
+        env_override("USER_RATE_LIMIT", &mut self.limits.user_rate_limit)?;
+        env_override("CHAT_RATE_LIMIT", &mut self.limits.chat_rate_limit)?;
+        quota_env_override("USER_QUOTA", &mut self.limits.user_quota)?;
+        quota_env_override("CHAT_QUOTA", &mut self.limits.chat_quota)?;
+        env_override("LOG_LEVEL", &mut self.logging.level)?;
+        env_flag("LOG_JSON", &mut self.logging.json);
+        env_flag("LOG_REDACT_CONTENT", &mut self.logging.redact_content);
+        if let Some(listen) = env_value("METRICS_LISTEN")? {
+            self.metrics.listen = Some(listen);
+        }
+        if let Ok(url) = std::env::var("WEBHOOK_URL") {
+            self.webhook.url = Some(url);
+        }
+        env_override("WEBHOOK_LISTEN", &mut self.webhook.listen)?;
+        let retention = &mut self.retention;
+        env_limit(
+            "RETENTION_MAX_MESSAGE_AGE_DAYS",
+            &mut retention.max_message_age_days,
+        )?;
+        env_limit(
+            "RETENTION_MAX_CONVERSATIONS",
+            &mut retention.max_conversations,
+        )?;
+        env_limit("RETENTION_MAX_MESSAGES", &mut retention.max_messages)?;
+        env_limit(
+            "RETENTION_INACTIVE_CHAT_DAYS",
+            &mut retention.inactive_chat_days,
+        )?;
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        for (name, backend) in &self.backends {
+            if !backend.url.starts_with("http://") && !backend.url.starts_with("https://") {
+                bail!(
+                    "backend `{name}` needs an http(s) url, not `{}`",
+                    backend.url
+                );
+            }
+            if backend.model.is_empty() {
+                bail!("backend `{name}` has no model");
+            }
+        }
+        let Some(default) = self.backends.get(&self.default_backend) else {
+            bail!(
+                "default_backend `{}` isn't one of the backends ({})",
+                self.default_backend,
+                self.backends.keys().cloned().collect::<Vec<_>>().join(", ")
+            );
+        };
+        if let Some(var) = &default.api_key_env {
+            if secret_from_env(var)?.is_none() {
+                bail!(
+                    "backend `{}` needs its API key in {var} or {var}_FILE",
+                    self.default_backend
+                );
+            }
+        }
+        tracing_subscriber::EnvFilter::try_new(&self.logging.level)
+            .with_context(|| format!("Invalid logging.level `{}`", self.logging.level))?;
+        if let Some(url) = &self.webhook.url {
+            let parsed =
+                url::Url::parse(url).with_context(|| format!("Invalid webhook.url `{url}`"))?;
+            if parsed.scheme() != "https" && self.webhook.set_webhook {
+                bail!("webhook.url must be https, Telegram won't post anywhere else");
+            }
+        }
+        if self.autosave_seconds == 0 {
+            bail!("autosave_seconds must be more than 0");
+        }
+        if self.storage_path.exists() && !self.storage_path.is_dir() {
+            bail!(
+                "storage_path {} isn't a directory",
+                self.storage_path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// What changed from `self` to `new`, one line per setting
+    pub fn diff(&self, new: &Self) -> Vec<String> {
+        let mut changes = vec![];
+        macro_rules! compare {
+            ($($field:ident).+ $(, $note:literal)?) => {
+                if self.$($field).+ != new.$($field).+ {
+                    changes.push(format!(
+                        concat!(stringify!($($field).+), $(" ", $note,)? ": {:?} -> {:?}"),
+                        self.$($field).+,
+                        new.$($field).+,
+                    ));
+                }
+            };
+        }
+        compare!(default_backend);
+        let names = self
+            .backends
+            .keys()
+            .chain(new.backends.keys())
+            .collect::<BTreeSet<_>>();
+        for name in names {
+            match (self.backends.get(name), new.backends.get(name)) {
+                (Some(old), Some(new)) if old != new => {
+                    changes.push(format!("backends.{name}: {old:?} -> {new:?}"));
+                }
+                (None, Some(new)) => changes.push(format!("backends.{name}: added {new:?}")),
+                (Some(_), None) => changes.push(format!("backends.{name}: removed")),
+                _ => {}
+            }
+        }
+        compare!(storage_path, "(needs a restart)");
+        compare!(autosave_seconds, "(needs a restart)");
+        compare!(backups, "(needs a restart)");
+        compare!(backup_interval_minutes, "(needs a restart)");
+        compare!(shutdown_timeout_seconds);
+        compare!(owner_id, "(needs a restart)");
+        compare!(access.allowlist_only);
+        compare!(access.allowed_users);
+        compare!(access.allowed_chats);
+        compare!(access.unauthorized_message);
+        compare!(prompts.system);
+        compare!(limits.user_rate_limit);
+        compare!(limits.chat_rate_limit);
+        compare!(limits.user_quota);
+        compare!(limits.chat_quota);
+        compare!(logging.level, "(needs a restart)");
+        compare!(logging.json, "(needs a restart)");
+        compare!(logging.redact_content);
+        compare!(metrics.listen, "(needs a restart)");
+        compare!(webhook.url, "(needs a restart)");
+        compare!(webhook.listen, "(needs a restart)");
+        compare!(webhook.set_webhook, "(needs a restart)");
+        compare!(retention);
+        compare!(encryption.key_env, "(needs a restart)");
+        compare!(encryption.old_keys_env, "(needs a restart)");
+        compare!(encryption.allow_plaintext, "(needs a restart)");
+        changes
+    }
+
+    pub fn default_backend(&self) -> anyhow::Result<Backend> {
+        self.backends[&self.default_backend]
+            .build(&self.default_backend, self.prompts.system.clone())
+            .with_context(|| format!("Couldn't set up backend `{}`", self.default_backend))
+    }
+}
+
+/// A secret from the environment variable `var`, or from the file named by `{var}_FILE`. Errors
+/// never include the secret itself.
+pub fn secret_from_env(var: &str) -> anyhow::Result<Option<SecretString>> {
+    if let Some(path) = std::env::var_os(format!("{var}_FILE")) {
+        let path = PathBuf::from(path);
+        let secret = std::fs::read_to_string(&path)
+            .with_context(|| format!("Couldn't read {var}_FILE {}", path.display()))?;
+        return Ok(Some(SecretString::new(secret.trim().to_string())));
+    }
+    Ok(std::env::var(var).ok().map(SecretString::new))
+}
+
+fn env_value<T: FromStr>(var: &str) -> anyhow::Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    std::env::var(var)
+        .ok()
+        .map(|value| value.trim().parse())
+        .transpose()
+        .with_context(|| format!("{var} is invalid"))
+}
+
+fn env_override<T: FromStr>(var: &str, target: &mut T) -> anyhow::Result<()>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(value) = env_value(var)? {
+        *target = value;
+    }
+    Ok(())
+}
+
+/// On for "1" or "true", off for anything else
+fn env_flag(var: &str, target: &mut bool) {
+    if let Ok(value) = std::env::var(var) {
+        *target = value == "1" || value == "true";
+    }
+}
+
+/// Comma separated list of Telegram IDs
+fn env_list<T: FromStr>(var: &str) -> anyhow::Result<Option<Vec<T>>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let Ok(list) = std::env::var(var) else {
+        return Ok(None);
+    };
+    list.split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .with_context(|| format!("{var} has invalid ID `{id}`"))
+        })
+        .collect::<anyhow::Result<_>>()
+        .map(Some)
+}
+
+/// `{prefix}_DAILY_REQUESTS`, `{prefix}_DAILY_TOKENS`, `{prefix}_MONTHLY_REQUESTS` and
+/// `{prefix}_MONTHLY_TOKENS`
+fn quota_env_override(prefix: &str, quota: &mut Quota) -> anyhow::Result<()> {
+    let limits = [
+        ("DAILY_REQUESTS", &mut quota.daily_requests),
+        ("DAILY_TOKENS", &mut quota.daily_tokens),
+        ("MONTHLY_REQUESTS", &mut quota.monthly_requests),
+        ("MONTHLY_TOKENS", &mut quota.monthly_tokens),
+    ];
+    for (name, limit) in limits {
+        env_limit(&format!("{prefix}_{name}"), limit)?;
+    }
+    Ok(())
+}
+
+/// Sets an optional limit, unset limits stay as they are
+fn env_limit<T: FromStr>(var: &str, limit: &mut Option<T>) -> anyhow::Result<()>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(value) = env_value(var)? {
+        *limit = Some(value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::ExposeSecret;
+
+    const SECRET: &str = "sk-test-do-not-leak";
+
+    /// A file in the temp dir unique to the test, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn model() -> OpenAIModel {
+        OpenAIModel::new_with_token(
+            "http://localhost".into(),
+            "model".into(),
+            &SecretString::new(SECRET.into()),
+        )
+    }
+
+    #[test]
+    fn debug_output_leaves_out_the_key() {
+        let model = model();
+        assert!(!format!("{model:?}").contains(SECRET));
+        let backend = Backend::OpenAI(model);
+        assert!(!format!("{backend:?}").contains(SECRET));
+        assert!(!format!("{backend:#?}").contains(SECRET));
+    }
+
+    // Every test uses its own variables, as tests run in parallel in the same environment
+    #[test]
+    fn file_wins_over_the_variable_and_is_trimmed() {
+        let file = TempFile::new("secret-file-wins", format!("  {SECRET}\n\n").as_bytes());
+        std::env::set_var("TEST_FILE_WINS", "from the variable");
+        std::env::set_var("TEST_FILE_WINS_FILE", &file.0);
+        let secret = secret_from_env("TEST_FILE_WINS").unwrap().unwrap();
+        assert_eq!(secret.expose_secret(), SECRET);
+    }
+
+    #[test]
+    fn variable_is_used_without_a_file() {
+        std::env::set_var("TEST_PLAIN_SECRET", SECRET);
+        let secret = secret_from_env("TEST_PLAIN_SECRET").unwrap().unwrap();
+        assert_eq!(secret.expose_secret(), SECRET);
+        assert!(secret_from_env("TEST_UNSET_SECRET").unwrap().is_none());
+    }
+
+    #[test]
+    fn missing_file_error_leaves_out_the_secret() {
+        std::env::set_var("TEST_MISSING_FILE", SECRET);
+        std::env::set_var("TEST_MISSING_FILE_FILE", "/nonexistent/secret");
+        let error = secret_from_env("TEST_MISSING_FILE").unwrap_err();
+        let error = format!("{error:?}");
+        assert!(error.contains("TEST_MISSING_FILE_FILE"));
+        assert!(!error.contains(SECRET));
+    }
+
+    #[test]
+    fn unreadable_file_error_leaves_out_the_secret() {
+        let mut contents = SECRET.as_bytes().to_vec();
+        contents.extend([0xff, 0xfe]);
+        let file = TempFile::new("secret-invalid-utf8", &contents);
+        std::env::set_var("TEST_INVALID_FILE_FILE", &file.0);
+        let error = secret_from_env("TEST_INVALID_FILE").unwrap_err();
+        assert!(!format!("{error:?}").contains(SECRET));
+    }
+
+    #[test]
+    fn missing_key_error_names_the_variables() {
+        let backend = BackendConfig {
+            kind: BackendKind::OpenAI,
+            url: "http://localhost".into(),
+            model: "model".into(),
+            api_key_env: Some("TEST_UNSET_KEY".into()),
+        };
+        let error = backend.build("test", None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Neither TEST_UNSET_KEY nor TEST_UNSET_KEY_FILE is set"
+        );
+    }
+
+    #[test]
+    fn read_skips_the_backend_checks() {
+        let file = TempFile::new(
+            "config-read.toml",
+            br#"default_backend = "remote"
+
+[backends.remote]
+type = "openai"
+url = "https://example.com/v1"
+model = "model"
+api_key_env = "TEST_READ_UNSET_KEY"
+
+[encryption]
+key_env = "TEST_READ_STORAGE_KEY"
+"#,
+        );
+        let error = Config::load(Some(&file.0)).unwrap_err();
+        assert!(format!("{error:#}").contains("needs its API key in TEST_READ_UNSET_KEY"));
+        let config = Config::read(Some(&file.0)).unwrap();
+        assert_eq!(
+            config.encryption.key_env.as_deref(),
+            Some("TEST_READ_STORAGE_KEY")
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use anyhow::{anyhow, bail, Context};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, EncryptionConfig};
+
+/// Encrypted files start with this, anything else is plain JSON
+const ENCRYPTED_PREFIX: &[u8] = b"{\"encrypted\":";
+
+/// An encrypted file. The name of the file it was saved as is authenticated along with the
+/// contents, so files can't be swapped for each other.
+#[derive(Serialize, Deserialize)]
+struct EncryptedFile {
+    encrypted: Sealed,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    file: String,
+    key_id: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+struct Key {
+    /// Start of the key's SHA-256, to find the right key without trying them all
+    id: String,
+    key: LessSafeKey,
+}
+
+impl Key {
+    fn parse(hex_key: &str, var: &str) -> anyhow::Result<Self> {
+        let bytes = hex::decode(hex_key.trim())
+            .ok()
+            .filter(|bytes| bytes.len() == AES_256_GCM.key_len())
+            .with_context(|| {
+                format!("{var} must be 64 hex characters, e.g. from `openssl rand -hex 32`")
+            })?;
+        let key = UnboundKey::new(&AES_256_GCM, &bytes).map_err(|_| anyhow!("Invalid {var}"))?;
+        Ok(Self {
+            id: hex::encode(&digest(&SHA256, &bytes).as_ref()[..4]),
+            key: LessSafeKey::new(key),
+        })
+    }
+}
+
+/// Keys for the saved files. Files are encrypted with the current key if there is one, and can be
+/// read with it or any of the old ones. Plain files are only read without a key, or when
+/// `allow_plaintext` is set to encrypt them.
+pub struct Keys {
+    current: Option<Key>,
+    old: Vec<Key>,
+    allow_plaintext: bool,
+    rng: SystemRandom,
+}
+
+impl Keys {
+    pub fn from_config(config: &EncryptionConfig) -> anyhow::Result<Self> {
+        let current = match &config.key_env {
+            Some(var) => {
+                let key = config::secret_from_env(var)?
+                    .with_context(|| format!("Neither {var} nor {var}_FILE is set"))?;
+                Some(Key::parse(key.expose_secret(), var)?)
+            }
+            None => None,
+        };
+        let mut old = Vec::new();
+        if let Some(var) = &config.old_keys_env {
+            if let Some(keys) = config::secret_from_env(var)? {
+                for key in keys
+                    .expose_secret()
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|key| !key.is_empty())
+                {
+                    old.push(Key::parse(key, var)?);
+                }
+            }
+        }
+        Ok(Self {
+            current,
+            old,
+            allow_plaintext: config.allow_plaintext,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Encrypts `contents` for saving as `file`, or leaves them alone without a key
+    pub fn encrypt(&self, file: &str, contents: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let Some(key) = &self.current else {
+            return Ok(contents);
+        };
+        let mut nonce = [0; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("Couldn't generate a nonce"))?;
+        let mut sealed = contents;
+        key.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(file.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| anyhow!("Couldn't encrypt {file}"))?;
+        Ok(serde_json::to_vec(&EncryptedFile {
+            encrypted: Sealed {
+                file: file.into(),
+                key_id: key.id.clone(),
+                nonce: BASE64.encode(nonce),
+                ciphertext: BASE64.encode(sealed),
+            },
+        })?)
+    }
+
+    /// Decrypts the contents of a saved file. Plain files are returned as they are without a
+    /// key, and refused with one unless `allow_plaintext` is set, so a file replaced with plain
+    /// JSON isn't silently trusted. `file` is the name it was expected to be saved as, if known.
+    pub fn decrypt(&self, file: Option<&str>, contents: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if !contents.starts_with(ENCRYPTED_PREFIX) {
+            if self.current.is_none() {
+                return Ok(contents);
+            }
+            if !self.allow_plaintext {
+                bail!(
+                    "This file isn't encrypted. If encryption was just turned on, set \
+                     allow_plaintext = true in [encryption] to read it once"
+                );
+            }
+            tracing::warn!(
+                "Reading {} unencrypted, it will be encrypted when it's next saved. Turn \
+                 allow_plaintext off once it is",
+                file.unwrap_or("the file")
+            );
+            return Ok(contents);
+        }
+        let EncryptedFile { encrypted } = serde_json::from_slice(&contents)?;
+        if let Some(file) = file.filter(|&file| file != encrypted.file) {
+            bail!("This is an encrypted {}, not {file}", encrypted.file);
+        }
+        let key = self
+            .current
+            .iter()
+            .chain(&self.old)
+            .find(|key| key.id == encrypted.key_id)
+            .with_context(|| {
+                format!(
+                    "Encrypted with key {}, which isn't the current key or one of the old keys",
+                    encrypted.key_id
+                )
+            })?;
+        let nonce = BASE64
+            .decode(&encrypted.nonce)
+            .ok()
+            .and_then(|nonce| Nonce::try_assume_unique_for_key(&nonce).ok())
+            .context("Invalid nonce")?;
+        let mut plain = BASE64
+            .decode(&encrypted.ciphertext)
+            .context("Invalid ciphertext")?;
+        let len = key
+            .key
+            .open_in_place(nonce, Aad::from(encrypted.file.as_bytes()), &mut plain)
+            .map_err(|_| anyhow!("Couldn't decrypt, the file was changed or damaged"))?
+            .len();
+        plain.truncate(len);
+        Ok(plain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const OTHER_KEY: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+    const CONTENTS: &[u8] = b"{\"version\":1,\"data\":{}}";
+
+    /// Keys from a key and old keys, in variables named after the test so tests don't clash
+    fn keys(test: &str, key: &str, old_keys: Option<&str>, allow_plaintext: bool) -> Keys {
+        let key_env = format!("ENCRYPTION_TEST_{test}_KEY");
+        std::env::set_var(&key_env, key);
+        let old_keys_env = old_keys.map(|old_keys| {
+            let var = format!("ENCRYPTION_TEST_{test}_OLD_KEYS");
+            std::env::set_var(&var, old_keys);
+            var
+        });
+        Keys::from_config(&EncryptionConfig {
+            key_env: Some(key_env),
+            old_keys_env,
+            allow_plaintext,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let keys = keys("ROUND_TRIP", KEY, None, false);
+        let encrypted = keys.encrypt("chats.json", CONTENTS.to_vec()).unwrap();
+        assert!(encrypted.starts_with(ENCRYPTED_PREFIX));
+        assert!(!encrypted
+            .windows(CONTENTS.len())
+            .any(|part| part == CONTENTS));
+        assert_eq!(
+            keys.decrypt(Some("chats.json"), encrypted.clone()).unwrap(),
+            CONTENTS
+        );
+        assert_eq!(keys.decrypt(None, encrypted).unwrap(), CONTENTS);
+    }
+
+    #[test]
+    fn old_keys_still_decrypt() {
+        let old = keys("OLD_KEYS_BEFORE", OTHER_KEY, None, false);
+        let encrypted = old.encrypt("chats.json", CONTENTS.to_vec()).unwrap();
+
+        let rotated = keys(
+            "OLD_KEYS_AFTER",
+            KEY,
+            Some(&format!("{OTHER_KEY}, ")),
+            false,
+        );
+        assert_eq!(
+            rotated
+                .decrypt(Some("chats.json"), encrypted.clone())
+                .unwrap(),
+            CONTENTS
+        );
+        let error = keys("OLD_KEYS_FORGOTTEN", KEY, None, false)
+            .decrypt(Some("chats.json"), encrypted)
+            .unwrap_err();
+        assert!(error.to_string().contains("isn't the current key"));
+    }
+
+    #[test]
+    fn swapped_files_are_refused() {
+        let keys = keys("SWAPPED", KEY, None, false);
+        let encrypted = keys.encrypt("memories.json", CONTENTS.to_vec()).unwrap();
+        let error = keys
+            .decrypt(Some("chats.json"), encrypted.clone())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "This is an encrypted memories.json, not chats.json"
+        );
+
+        // Renaming it inside the file doesn't help, the name is authenticated
+        let mut file: EncryptedFile = serde_json::from_slice(&encrypted).unwrap();
+        file.encrypted.file = "chats.json".into();
+        let renamed = serde_json::to_vec(&file).unwrap();
+        assert!(keys.decrypt(Some("chats.json"), renamed).is_err());
+    }
+
+    #[test]
+    fn tampered_files_are_refused() {
+        let keys = keys("TAMPERED", KEY, None, false);
+        let encrypted = keys.encrypt("chats.json", CONTENTS.to_vec()).unwrap();
+        let mut file: EncryptedFile = serde_json::from_slice(&encrypted).unwrap();
+        let mut ciphertext = BASE64.decode(&file.encrypted.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        file.encrypted.ciphertext = BASE64.encode(ciphertext);
+        let error = keys
+            .decrypt(Some("chats.json"), serde_json::to_vec(&file).unwrap())
+            .unwrap_err();
+        assert!(error.to_string().contains("changed or damaged"));
+    }
+
+    #[test]
+    fn plain_files_need_allow_plaintext_with_a_key() {
+        let none = Keys::from_config(&EncryptionConfig::default()).unwrap();
+        assert_eq!(
+            none.decrypt(Some("chats.json"), CONTENTS.to_vec()).unwrap(),
+            CONTENTS
+        );
+
+        let error = keys("PLAIN_REFUSED", KEY, None, false)
+            .decrypt(Some("chats.json"), CONTENTS.to_vec())
+            .unwrap_err();
+        assert!(error.to_string().contains("isn't encrypted"));
+
+        let allowed = keys("PLAIN_ALLOWED", KEY, None, true);
+        assert_eq!(
+            allowed
+                .decrypt(Some("chats.json"), CONTENTS.to_vec())
+                .unwrap(),
+            CONTENTS
+        );
+    }
+}
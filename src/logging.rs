@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LoggingConfig;
+
+static REDACT_CONTENT: AtomicBool = AtomicBool::new(false);
+
+/// Logs to stdout, or to stderr for commands that print their output. `RUST_LOG` wins over the
+/// configured level. Every update is a span, which is logged with its duration when it closes.
+pub fn init(config: &LoggingConfig, stderr: bool) -> anyhow::Result<()> {
+    set_redact_content(config.redact_content);
+    let filter =
+        EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(&config.level))?;
+    let writer = if stderr {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+    if config.json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+    Ok(())
+}
+
+pub fn set_redact_content(redact: bool) {
+    REDACT_CONTENT.store(redact, Ordering::Relaxed);
+}
+
+/// Message text for logs, only its length when `redact_content` is on
+pub fn content(text: &str) -> Content<'_> {
+    Content(text)
+}
+
+pub struct Content<'a>(&'a str);
+
+impl std::fmt::Display for Content<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if REDACT_CONTENT.load(Ordering::Relaxed) {
+            write!(f, "[{} chars redacted]", self.0.chars().count())
+        } else {
+            f.write_str(self.0)
+        }
+    }
+}
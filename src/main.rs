@@ -1,22 +1,55 @@
 #![warn(clippy::all, clippy::pedantic)]
+use teloxide::dispatching::{DefaultKey, UpdateHandler};
+use teloxide::net::Download;
 use teloxide::prelude::*;
-use teloxide::types::ChatAction;
+use teloxide::types::{BotCommandScope, ChatAction, Document, InputFile, Me, MessageEntity};
+use teloxide::update_listeners::UpdateListener;
+use teloxide::RequestError;
 
+use anyhow::Context;
+use chrono::Utc;
+use clap::Parser;
+use secrecy::ExposeSecret;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
+use tracing::{debug, error, info, warn, Instrument};
 
 mod ai;
 mod bot;
+mod config;
+mod encryption;
+mod logging;
+mod metrics;
 mod models;
-use ai::openai::OpenAIModel;
+mod storage;
+mod webhook;
 use ai::Model;
-use bot::CommandResult;
-use models::{Backend, ChatMessage, Role, UserState};
+use bot::access::{access_command, AccessList};
+use bot::keyboard::{forget_me_keyboard, reply_keyboard, ForgetMe, ReplyAction};
+use bot::permissions::{Access, PermissionChecker};
+use bot::ratelimit::{RateLimit, RateLimiter};
+use bot::{Command, CommandResult};
+use config::Config;
+use metrics::METRICS;
+use models::export;
+use models::retention::{Purged, Retention};
+use models::usage::{Quota, Quotas, Usage, UsageStore};
+use models::{Backend, Branches, ChatMessage, Conversation, Role, UserMemory, UserState};
+use storage::{Chats, Memories, Storage};
 
-const OPENAI_API_URL: &str = "http://localhost:5000/v1";
-const GROQ_API_URL: &str = "https://api.groq.com/openai/v1";
-const GROQ_MODEL: &str = "llama3-70b-8192";
+const TELEGRAM_MAX_MESSAGE_LENGTH: usize = 4096;
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+const RETENTION_INTERVAL: Duration = Duration::from_hours(1);
+/// Largest file /import accepts, Telegram lets bots download up to 20 MB
+const MAX_IMPORT_BYTES: u32 = 10 * 1024 * 1024;
+const ABORTED_MESSAGE: &str =
+    "Sorry, I had to restart before I could finish replying. Please try again in a minute.";
+const FORGET_ME_MESSAGE: &str = "This permanently deletes your conversations with me, everything I remember about you and your usage records, and removes your messages from the history of group chats. Backups of the bot's data keep a copy until they're replaced by newer ones. Are you sure?";
+const SLOW_DOWN_MESSAGE: &str = "Whoa, slow down a little! I'll read everything you send and answer it all together with my next reply.";
 
 async fn typing_while<T>(
     bot: &Bot,
@@ -35,164 +68,1605 @@ async fn typing_while<T>(
     }
 }
 
+/// Sends a new reply with the action buttons, removing the buttons from the previous reply
+async fn send_reply(
+    bot: &Bot,
+    chat_id: ChatId,
+    conversation: &mut Conversation,
+    content: String,
+    branches: Branches,
+) -> anyhow::Result<()> {
+    let stale = branches
+        .before
+        .last()
+        .and_then(|branch| branch.first())
+        .into_iter()
+        .chain(conversation.messages.iter().rev())
+        .find(|m| m.from == Role::Assistant)
+        .and_then(|m| m.id);
+    if let Some(stale) = stale {
+        let _ = bot.edit_message_reply_markup(chat_id, stale).await;
+    }
+    let sent = bot
+        .send_message(chat_id, &content)
+        .reply_markup(reply_keyboard(&branches))
+        .await?;
+    let mut reply = ChatMessage::new(content, None).with_id(sent.id);
+    reply.branches = branches;
+    conversation.messages.push(reply);
+    Ok(())
+}
+
+async fn handle_command_result(
+    bot: &Bot,
+    ctx: &BotContext,
+    chat_id: ChatId,
+    user_id: Option<UserId>,
+    result: CommandResult<'_>,
+    memory: &mut UserMemory,
+) -> anyhow::Result<()> {
+    #[allow(clippy::match_wildcard_for_single_variants)]
+    match result {
+        //CommandResult::DoNothing => {}
+        CommandResult::ReplyToUser(msg) => {
+            bot.send_message(chat_id, msg).await?;
+        }
+        CommandResult::DeleteMessages(msg, ids) => {
+            for id in ids {
+                // Messages older than 48 hours can't be deleted, that's fine
+                let _ = bot.delete_message(chat_id, id).await;
+            }
+            bot.send_message(chat_id, msg).await?;
+        }
+        CommandResult::SendFiles(files) => {
+            for (name, contents) in files {
+                bot.send_document(chat_id, InputFile::memory(contents).file_name(name))
+                    .await?;
+            }
+        }
+        CommandResult::ConfirmForgetMe => {
+            let Some(user_id) = user_id else {
+                bot.send_message(chat_id, "I can't tell who you are, sorry.")
+                    .await?;
+                return Ok(());
+            };
+            bot.send_message(chat_id, FORGET_ME_MESSAGE)
+                .reply_markup(forget_me_keyboard(user_id))
+                .await?;
+        }
+        CommandResult::RegenerateLastMessage(conversation, branches) => {
+            let result = typing_while(
+                bot,
+                chat_id,
+                ctx.backend()
+                    .reply(conversation, private_memory(chat_id, memory)),
+            )
+            .await?;
+            ctx.record_usage(user_id, chat_id, result.usage);
+            info!(reply = %logging::content(&result.content), "Regenerated reply");
+            remember_all(memory, result.remembered);
+            send_reply(bot, chat_id, conversation, result.content, branches).await?;
+        } //CommandResult::GenerateDescription(conversation) => {
+          //    let result =
+          //        typing_while(bot, chat_id, default_backend.description(conversation)).await?;
+          //    bot.send_message(chat_id, format!("New conversation description: {result}"))
+          //        .await?;
+          //    println!("New description for chat {}: {}", conversation.name, result);
+          //    conversation.description = Some(result);
+          //}
+    }
+    Ok(())
+}
+
 async fn handle_msg(
     bot: &Bot,
+    ctx: &Ctx,
     msg: Message,
     mut state: UserState,
-    default_backend: &Backend,
-) -> anyhow::Result<UserState> {
+    mut memory: UserMemory,
+    chats: &Chats,
+    memories: &Memories,
+) -> anyhow::Result<(UserState, UserMemory)> {
     let chat_id = msg.chat.id;
     let username = msg
         .from()
         .map_or_else(|| "UNKNOWN".into(), teloxide::types::User::full_name);
-    println!("{}: {}", username, msg.text().unwrap_or(""));
-    let group_chat = msg.chat.is_group();
+    info!(from = %username, text = %logging::content(msg.text().unwrap_or("")), "Message");
+    let user_id = msg.from().map(|user| user.id);
+    let group_chat = bot::is_group_chat(&msg.chat);
+    if let Some(document) = msg
+        .document()
+        .filter(|document| wants_import(ctx, &msg, document))
+    {
+        handle_import(bot, ctx, &msg, document, &mut state).await?;
+        return Ok((state, memory));
+    }
     let Some(text) = msg.text() else {
         bot.send_message(chat_id, "This bot only supports text messages! (for now)")
             .await?;
-        return Ok(state);
+        return Ok((state, memory));
     };
     if text.starts_with('/') {
         // handle command
-        let result = bot::handle_command(text, &mut state)?;
-
-        #[allow(clippy::match_wildcard_for_single_variants)]
-        match result {
-            //CommandResult::DoNothing => {}
-            CommandResult::ReplyToUser(msg) => {
-                bot.send_message(chat_id, msg).await?;
-            }
-            CommandResult::RegenerateLastMessage(conversation) => {
-                let result =
-                    typing_while(bot, chat_id, default_backend.reply(conversation)).await?;
-                bot.send_message(chat_id, &result).await?;
-                println!("BOT: {result}");
-                conversation.messages.push(ChatMessage::new(result, None));
-            } //CommandResult::GenerateDescription(conversation) => {
-              //    let result =
-              //        typing_while(bot, chat_id, default_backend.description(conversation)).await?;
-              //    bot.send_message(chat_id, format!("New conversation description: {result}"))
-              //        .await?;
-              //    println!("New description for chat {}: {}", conversation.name, result);
-              //    conversation.description = Some(result);
-              //}
-        }
-        return Ok(state);
+        let command = match bot::parse_command(text, ctx.me.username()) {
+            Ok(Some(command)) => command,
+            Ok(None) => {
+                debug!("Ignoring command for another bot");
+                return Ok((state, memory));
+            }
+            Err(e) => {
+                bot.send_message(chat_id, e).await?;
+                return Ok((state, memory));
+            }
+        };
+        let required = bot::permissions::required_access(&command, group_chat, &state);
+        if !check_access(bot, &ctx.permissions, &msg.chat, user_id, required).await? {
+            bot.send_message(
+                chat_id,
+                format!(
+                    "Sorry, only {} can use /{} here.",
+                    required.describe(),
+                    command.name()
+                ),
+            )
+            .await?;
+            return Ok((state, memory));
+        }
+        if let Some(reply) = context_command(ctx, &command, &msg, &state) {
+            METRICS.command(command.name());
+            bot.send_message(chat_id, reply).await?;
+            return Ok((state, memory));
+        }
+        if command == Command::Redo {
+            if let Some(refusal) = ctx.refusal(user_id, chat_id) {
+                if refusal.notify {
+                    bot.send_message(chat_id, refusal.text).await?;
+                }
+                return Ok((state, memory));
+            }
+        }
+        METRICS.command(command.name());
+        let reply_to = msg.reply_to_message().map(|m| m.id);
+        let result = bot::handle_command(
+            command,
+            reply_to,
+            group_chat,
+            &mut state,
+            private_memory_mut(chat_id, &mut memory),
+        )?;
+        handle_command_result(bot, ctx, chat_id, user_id, result, &mut memory).await?;
+        return Ok((state, memory));
     }
     // Non-command message, handle here
+    push_user_message(&mut state, &msg, text);
+    reply_to_conversation(bot, ctx, &msg, state, memory, chats, memories).await
+}
+
+/// JSON files are imported in private chats, and any file is with /import as its caption
+fn wants_import(ctx: &BotContext, msg: &Message, document: &Document) -> bool {
+    let captioned = msg.caption().is_some_and(|caption| {
+        matches!(
+            bot::parse_command(caption, ctx.me.username()),
+            Ok(Some(Command::Import))
+        )
+    });
+    let is_json = document
+        .mime_type
+        .as_ref()
+        .is_some_and(|mime| mime.essence_str() == "application/json")
+        || document
+            .file_name
+            .as_deref()
+            .and_then(|name| Path::new(name).extension())
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    captioned || (msg.chat.is_private() && is_json)
+}
+
+/// Imports an uploaded file as new conversations, the last of which becomes the current one
+async fn handle_import(
+    bot: &Bot,
+    ctx: &BotContext,
+    msg: &Message,
+    document: &Document,
+    state: &mut UserState,
+) -> anyhow::Result<()> {
+    let chat_id = msg.chat.id;
+    let user_id = msg.from().map(|user| user.id);
+    let command = Command::Import;
+    let required =
+        bot::permissions::required_access(&command, bot::is_group_chat(&msg.chat), state);
+    if !check_access(bot, &ctx.permissions, &msg.chat, user_id, required).await? {
+        bot.send_message(
+            chat_id,
+            format!("Sorry, only {} can use /import here.", required.describe()),
+        )
+        .await?;
+        return Ok(());
+    }
+    METRICS.command(command.name());
+    if document.file.size > MAX_IMPORT_BYTES {
+        bot.send_message(
+            chat_id,
+            format!(
+                "That file is too big, I can import up to {} MB.",
+                MAX_IMPORT_BYTES / 1024 / 1024
+            ),
+        )
+        .await?;
+        return Ok(());
+    }
+    let file = bot.get_file(&document.file.id).await?;
+    let mut contents = Vec::new();
+    bot.download_file(&file.path, &mut contents).await?;
+
+    let file_name = document.file_name.as_deref().unwrap_or("your file");
+    let username = msg
+        .from()
+        .map_or_else(|| "UNKNOWN".into(), teloxide::types::User::full_name);
+    let name = format!("Imported from {file_name}");
+    let conversations = match export::import(&contents, &name, &username) {
+        Ok(conversations) => conversations,
+        Err(e) => {
+            bot.send_message(chat_id, format!("Couldn't import {file_name}: {e:#}."))
+                .await?;
+            return Ok(());
+        }
+    };
+    let count = conversations.len();
+    let messages = conversations
+        .iter()
+        .map(|conversation| conversation.messages.len())
+        .sum::<usize>();
+    state.conversations.extend(conversations);
+    state.current_conversation = Some(state.conversations.len() - 1);
+    info!(conversations = count, messages, "Imported conversations");
+    let current = &state.conversations[state.conversations.len() - 1].name;
+    let reply = if count == 1 {
+        format!(
+            "Imported {messages} messages into \"{current}\", it's the current conversation now."
+        )
+    } else {
+        format!(
+            "Imported {messages} messages in {count} conversations. The last one, \"{current}\", is the current conversation now."
+        )
+    };
+    bot.send_message(chat_id, reply).await?;
+    Ok(())
+}
+
+/// Adds a user's text message to the current conversation
+fn push_user_message(state: &mut UserState, msg: &Message, text: &str) {
+    let username = msg
+        .from()
+        .map_or_else(|| "UNKNOWN".into(), teloxide::types::User::full_name);
+    let named_message = name_message(bot::is_group_chat(&msg.chat), &username, text);
+    state.get_or_create_conversation().messages.push(
+        ChatMessage::new(named_message, Some(username))
+            .with_id(msg.id)
+            .with_user_id(msg.from().map(|user| user.id)),
+    );
+}
+
+/// Replies to the current conversation, `msg` being the latest message in it
+async fn reply_to_conversation(
+    bot: &Bot,
+    ctx: &Ctx,
+    msg: &Message,
+    mut state: UserState,
+    mut memory: UserMemory,
+    chats: &Chats,
+    memories: &Memories,
+) -> anyhow::Result<(UserState, UserMemory)> {
+    let reply = generate_reply(bot, ctx, msg, &mut state, &mut memory, chats, memories).await?;
+    if let Some(content) = reply {
+        let conversation = state.get_or_create_conversation();
+        send_reply(bot, msg.chat.id, conversation, content, Branches::default()).await?;
+    }
+    Ok((state, memory))
+}
+
+/// Writes the reply to the current conversation without sending it, `msg` being the latest
+/// message in it. `None` if the bot isn't replying. When rate limited, the reply is scheduled
+/// for when the limit allows it.
+async fn generate_reply(
+    bot: &Bot,
+    ctx: &Ctx,
+    msg: &Message,
+    state: &mut UserState,
+    memory: &mut UserMemory,
+    chats: &Chats,
+    memories: &Memories,
+) -> anyhow::Result<Option<String>> {
+    let default_backend = ctx.backend();
+    let chat_id = msg.chat.id;
+    let user_id = msg.from().map(|user| user.id);
+    let group_chat = bot::is_group_chat(&msg.chat);
     let conversation = state.get_or_create_conversation();
-    let named_message = if group_chat {
+    // The message stays in the conversation, so rapid messages get answered together by the
+    // next reply
+    if let Some(refusal) = ctx.refusal(user_id, chat_id) {
+        // Groups only hear about it when talking to the bot, not on every message
+        let addressed = !group_chat
+            || msg
+                .text()
+                .is_some_and(|text| text.contains(&format!("@{}", ctx.me.username())))
+            || msg
+                .reply_to_message()
+                .and_then(|m| m.from())
+                .is_some_and(|user| user.id == ctx.me.id);
+        info!(reason = %refusal.text, "Not replying");
+        if refusal.notify && addressed {
+            bot.send_message(chat_id, refusal.text)
+                .reply_to_message_id(msg.id)
+                .await?;
+        }
+        if let Some(delay) = refusal.retry_after {
+            schedule_reply(
+                bot.clone(),
+                msg.clone(),
+                delay,
+                Arc::clone(chats),
+                Arc::clone(memories),
+                ctx,
+            );
+        }
+        return Ok(None);
+    }
+    if group_chat {
+        let (my_turn, usage) = default_backend.my_turn(conversation).await?;
+        ctx.record_usage(user_id, chat_id, usage);
+        if !my_turn {
+            info!("Bot chose not to reply");
+            return Ok(None);
+        }
+    }
+    let response = typing_while(
+        bot,
+        chat_id,
+        default_backend.reply(conversation, private_memory(chat_id, memory)),
+    )
+    .await?;
+    ctx.record_usage(user_id, chat_id, response.usage);
+    remember_all(memory, response.remembered);
+    info!(reply = %logging::content(&response.content), "Replied");
+    Ok(Some(response.content))
+}
+
+/// Replies once the chat has been quiet for `delay`, unless another message comes in first and
+/// cancels it, see `BotContext::cancel_pending_reply`
+fn schedule_reply(
+    bot: Bot,
+    msg: Message,
+    delay: Duration,
+    chats: Chats,
+    memories: Memories,
+    ctx: &Ctx,
+) {
+    let chat_id = msg.chat.id;
+    let user_id = msg.from().map(|user| user.id);
+    let task_ctx = Arc::clone(ctx);
+    let span = update_span("debounced reply", chat_id, user_id);
+    let pending = METRICS.pending_reply();
+    let handling = ctx.handling(chat_id);
+    // Held until the reply is in the map, so the task can't look for it before then
+    let mut pending_replies = ctx.pending_replies.lock().unwrap();
+    let handle = tokio::task::spawn(
+        async move {
+            let _pending = (pending, handling);
+            tokio::time::sleep(delay).await;
+            let _chat = task_ctx.lock_chat(chat_id).await;
+            let (mut state, mut memory) = load_state(&chats, &memories, chat_id, user_id);
+            let reply = generate_reply(
+                &bot,
+                &task_ctx,
+                &msg,
+                &mut state,
+                &mut memory,
+                &chats,
+                &memories,
+            )
+            .await;
+            let result = match reply {
+                Ok(Some(content)) => {
+                    // From here on the reply can't be cancelled, it could be sent before it's
+                    // stored. If it was cancelled while being written, the newer message that
+                    // cancelled it gets the reply instead.
+                    if !task_ctx.start_sending(chat_id) {
+                        return;
+                    }
+                    let conversation = state.get_or_create_conversation();
+                    send_reply(&bot, chat_id, conversation, content, Branches::default())
+                        .await
+                        .map(|()| (state, memory))
+                }
+                Ok(None) => Ok((state, memory)),
+                Err(e) => Err(e),
+            };
+            finish_update(&bot, &chats, &memories, chat_id, user_id, result, "reply").await;
+        }
+        .instrument(span),
+    );
+    pending_replies.insert(
+        chat_id,
+        PendingReply {
+            handle: Some(handle),
+            started: false,
+        },
+    );
+}
+
+/// A debounced reply, waiting for the chat to go quiet or being written
+struct PendingReply {
+    /// Taken when shutting down, to wait for it
+    handle: Option<tokio::task::JoinHandle<()>>,
+    /// Whether it has started sending the reply, after which it isn't cancelled anymore
+    started: bool,
+}
+
+/// Updates the stored copy of an edited message. If it was the last user turn of the current
+/// conversation, the bot's reply to it is regenerated and edited in place.
+async fn handle_edit(
+    bot: &Bot,
+    ctx: &BotContext,
+    msg: Message,
+    mut state: UserState,
+    mut memory: UserMemory,
+) -> anyhow::Result<(UserState, UserMemory)> {
+    let default_backend = ctx.backend();
+    let chat_id = msg.chat.id;
+    let Some(text) = msg.text() else {
+        return Ok((state, memory));
+    };
+    let Some((conv_idx, msg_idx)) = state.find_message(msg.id) else {
+        return Ok((state, memory));
+    };
+    let username = msg
+        .from()
+        .map_or_else(|| "UNKNOWN".into(), teloxide::types::User::full_name);
+    info!(from = %username, text = %logging::content(text), "Message edited");
+    let is_current = state.current_conversation == Some(conv_idx);
+    let conversation = &mut state.conversations[conv_idx];
+    conversation.messages[msg_idx].content =
+        name_message(bot::is_group_chat(&msg.chat), &username, text);
+
+    // Only regenerate if the edited message is directly followed by the bot's latest reply
+    if !is_current || msg_idx + 2 != conversation.messages.len() {
+        return Ok((state, memory));
+    }
+    let Some(ChatMessage {
+        from: Role::Assistant,
+        id: Some(reply_id),
+        ..
+    }) = conversation.messages.last()
+    else {
+        return Ok((state, memory));
+    };
+    let reply_id = *reply_id;
+    let user_id = msg.from().map(|user| user.id);
+    if let Some(refusal) = ctx.refusal(user_id, chat_id) {
+        if refusal.notify {
+            bot.send_message(chat_id, refusal.text).await?;
+        }
+        return Ok((state, memory));
+    }
+    let branches = conversation.messages.pop().unwrap().branches;
+    let response = typing_while(
+        bot,
+        chat_id,
+        default_backend.reply(conversation, private_memory(chat_id, &memory)),
+    )
+    .await?;
+    ctx.record_usage(user_id, chat_id, response.usage);
+    remember_all(&mut memory, response.remembered);
+    info!(reply = %logging::content(&response.content), "Rewrote reply");
+    bot.edit_message_text(chat_id, reply_id, &response.content)
+        .reply_markup(reply_keyboard(&branches))
+        .await?;
+    let mut reply = ChatMessage::new(response.content, None).with_id(reply_id);
+    reply.branches = branches;
+    conversation.messages.push(reply);
+    Ok((state, memory))
+}
+
+/// Handles the inline buttons under the bot's latest reply
+async fn handle_callback(
+    bot: &Bot,
+    ctx: &BotContext,
+    query: &CallbackQuery,
+    message: &Message,
+    mut state: UserState,
+    mut memory: UserMemory,
+) -> anyhow::Result<(UserState, UserMemory)> {
+    let chat_id = message.chat.id;
+    let Some(action) = query.data.as_deref().and_then(ReplyAction::parse) else {
+        bot.answer_callback_query(&query.id).await?;
+        return Ok((state, memory));
+    };
+    if action == ReplyAction::CopyAsCode {
+        bot.answer_callback_query(&query.id).await?;
+        let text = message.text().unwrap_or_default();
+        let length = text.encode_utf16().count();
+        bot.send_message(chat_id, text)
+            .entities([MessageEntity::pre(None, 0, length)])
+            .reply_to_message_id(message.id)
+            .await?;
+        return Ok((state, memory));
+    }
+    let current = state.current_conversation;
+    let found = state.find_message(message.id);
+    let is_latest = found.is_some_and(|(conv_idx, msg_idx)| {
+        Some(conv_idx) == current && msg_idx + 1 == state.conversations[conv_idx].messages.len()
+    });
+    let Some((conv_idx, msg_idx)) = found.filter(|_| {
+        is_latest || matches!(action, ReplyAction::PrevBranch | ReplyAction::NextBranch)
+    }) else {
+        let _ = bot.edit_message_reply_markup(chat_id, message.id).await;
+        bot.answer_callback_query(&query.id)
+            .text("This reply is outdated, only the latest one can be changed.")
+            .await?;
+        return Ok((state, memory));
+    };
+    // Every button but copying changes the conversation, so they're restricted along with /redo
+    let group_chat = bot::is_group_chat(&message.chat);
+    let required = bot::permissions::required_access(&Command::Redo, group_chat, &state);
+    if !check_access(
+        bot,
+        &ctx.permissions,
+        &message.chat,
+        Some(query.from.id),
+        required,
+    )
+    .await?
+    {
+        bot.answer_callback_query(&query.id)
+            .text(format!(
+                "Sorry, only {} can change replies here.",
+                required.describe()
+            ))
+            .await?;
+        return Ok((state, memory));
+    }
+    if !matches!(action, ReplyAction::PrevBranch | ReplyAction::NextBranch) {
+        if let Some(refusal) = ctx.refusal(Some(query.from.id), chat_id) {
+            bot.answer_callback_query(&query.id)
+                .text(refusal.text)
+                .await?;
+            return Ok((state, memory));
+        }
+    }
+    let conversation = &mut state.conversations[conv_idx];
+    match action {
+        ReplyAction::PrevBranch | ReplyAction::NextBranch => {
+            if !conversation.switch_branch(msg_idx, action == ReplyAction::NextBranch) {
+                bot.answer_callback_query(&query.id)
+                    .text("No more versions of this message.")
+                    .await?;
+                return Ok((state, memory));
+            }
+            bot.answer_callback_query(&query.id).await?;
+            // All versions of this message are shown in the same Telegram message
+            let selected = &mut conversation.messages[msg_idx];
+            selected.id = Some(message.id);
+            bot.edit_message_text(chat_id, message.id, &selected.content)
+                .reply_markup(reply_keyboard(&selected.branches))
+                .await?;
+        }
+        ReplyAction::Regenerate => {
+            bot.answer_callback_query(&query.id).await?;
+            let result = bot::handle_command(Command::Redo, None, group_chat, &mut state, None)?;
+            handle_command_result(bot, ctx, chat_id, Some(query.from.id), result, &mut memory)
+                .await?;
+        }
+        ReplyAction::Continue | ReplyAction::Shorter | ReplyAction::Longer => {
+            bot.answer_callback_query(&query.id).await?;
+            rewrite_reply(
+                bot,
+                ctx,
+                message,
+                query.from.id,
+                conversation,
+                action,
+                &mut memory,
+            )
+            .await?;
+        }
+        ReplyAction::CopyAsCode => unreachable!(),
+    }
+    Ok((state, memory))
+}
+
+/// Handles the buttons under the /forgetme confirmation. Deletes the user's private chat, memory
+/// and usage, and their messages in every other chat.
+async fn forget_me(
+    bot: &Bot,
+    ctx: &BotContext,
+    query: &CallbackQuery,
+    message: &Message,
+    action: ForgetMe,
+    chats: &Chats,
+    memories: &Memories,
+) -> anyhow::Result<()> {
+    let user_id = query.from.id;
+    if action.user_id() != user_id {
+        bot.answer_callback_query(&query.id)
+            .text("Only the person who asked can answer this.")
+            .await?;
+        return Ok(());
+    }
+    bot.answer_callback_query(&query.id).await?;
+    if let ForgetMe::Cancel(_) = action {
+        bot.edit_message_text(message.chat.id, message.id, "Okay, nothing was deleted.")
+            .await?;
+        return Ok(());
+    }
+    // Only groups can have their messages besides their own private chat. Messages from before
+    // senders were tracked are matched by name, which is only safe among a group's members.
+    let name = query.from.full_name();
+    let groups = chats
+        .lock()
+        .unwrap()
+        .keys()
+        .copied()
+        .filter(|chat_id| !chat_id.is_user())
+        .collect::<Vec<_>>();
+    let mut removed = 0;
+    for chat_id in groups {
+        removed += ctx
+            .update_chat(chats, chat_id, |chats| {
+                chats
+                    .get_mut(&chat_id)
+                    .map_or(0, |state| state.remove_user_messages(user_id, Some(&name)))
+            })
+            .await;
+    }
+    let private_chat = ChatId::from(user_id);
+    ctx.cancel_pending_reply(private_chat).await;
+    // Memories are written back by updates in the private chat too
+    ctx.update_chat(chats, private_chat, |chats| {
+        chats.remove(&private_chat);
+        memories.lock().unwrap().remove(&user_id);
+        let mut usage = ctx.usage.lock().unwrap();
+        usage.users.remove(&user_id);
+        usage.chats.remove(&private_chat);
+    })
+    .await;
+    info!(removed, "Deleted a user's data");
+    bot.edit_message_text(
+        message.chat.id,
+        message.id,
+        "Done, I've deleted everything I had stored about you.",
+    )
+    .await?;
+    Ok(())
+}
+
+/// Handles the commands that work on the bot context instead of the chat's state
+fn context_command(
+    ctx: &BotContext,
+    command: &Command,
+    msg: &Message,
+    state: &UserState,
+) -> Option<String> {
+    match command {
+        Command::Allow(_) | Command::Block(_) | Command::Invite(_) => {
+            let reply_to_user = msg.reply_to_message().and_then(|m| m.from()).map(|u| u.id);
+            Some(access_command(
+                command,
+                &mut ctx.access.lock().unwrap(),
+                reply_to_user,
+                &msg.chat,
+                ctx.me.username(),
+            ))
+        }
+        Command::Usage => Some(ctx.usage_report(
+            msg.from().map(|user| user.id),
+            msg.chat.id,
+            bot::is_group_chat(&msg.chat),
+        )),
+        Command::Retention => Some(ctx.retention().report(state)),
+        _ => None,
+    }
+}
+
+/// Continues, shortens or lengthens the bot's latest reply, shown in `message`
+async fn rewrite_reply(
+    bot: &Bot,
+    ctx: &BotContext,
+    message: &Message,
+    user_id: UserId,
+    conversation: &mut Conversation,
+    action: ReplyAction,
+    memory: &mut UserMemory,
+) -> anyhow::Result<()> {
+    let chat_id = message.chat.id;
+    let instruction = action
+        .instruction()
+        .context("Reply action has no instruction")?;
+    let response = typing_while(
+        bot,
+        chat_id,
+        ctx.backend().reply_with_instruction(
+            conversation,
+            private_memory(chat_id, memory),
+            instruction,
+        ),
+    )
+    .await?;
+    ctx.record_usage(Some(user_id), chat_id, response.usage);
+    remember_all(memory, response.remembered);
+    info!(?action, reply = %logging::content(&response.content), "Rewrote reply");
+    if action == ReplyAction::Continue {
+        let last = conversation.messages.last_mut().unwrap();
+        let combined = format!(
+            "{} {}",
+            last.content.trim_end(),
+            response.content.trim_start()
+        );
+        if combined.encode_utf16().count() > TELEGRAM_MAX_MESSAGE_LENGTH {
+            return send_reply(
+                bot,
+                chat_id,
+                conversation,
+                response.content,
+                Branches::default(),
+            )
+            .await;
+        }
+        bot.edit_message_text(chat_id, message.id, &combined)
+            .reply_markup(reply_keyboard(&last.branches))
+            .await?;
+        last.content = combined;
+        return Ok(());
+    }
+    // Keep the original around as a branch, so it can be flipped back to
+    let branches = conversation.branch_from(conversation.messages.len() - 1);
+    bot.edit_message_text(chat_id, message.id, &response.content)
+        .reply_markup(reply_keyboard(&branches))
+        .await?;
+    let mut reply = ChatMessage::new(response.content, None).with_id(message.id);
+    reply.branches = branches;
+    conversation.messages.push(reply);
+    Ok(())
+}
+
+/// Lets users in with an invite code (`/start <code>`), or tells them they can't use the bot
+async fn handle_unauthorized(bot: &Bot, ctx: &BotContext, msg: &Message) -> anyhow::Result<()> {
+    let text = msg.text().unwrap_or_default();
+    let command = bot::parse_command(text, ctx.me.username()).ok().flatten();
+    // Don't answer every message in groups, only commands
+    if bot::is_group_chat(&msg.chat) && command.is_none() {
+        return Ok(());
+    }
+    if let (Some(Command::Start(code)), Some(user)) = (&command, msg.from()) {
+        let redeemed = !code.is_empty() && ctx.access.lock().unwrap().redeem_invite(code, user.id);
+        if redeemed {
+            info!(
+                user_id = user.id.0,
+                "{} joined with invite code",
+                user.full_name()
+            );
+            bot.send_message(
+                msg.chat.id,
+                "Welcome! Just send me a message to start chatting. See /help for commands.",
+            )
+            .await?;
+            return Ok(());
+        }
+    }
+    let unauthorized = ctx
+        .access
+        .lock()
+        .unwrap()
+        .unauthorized_message()
+        .to_string();
+    bot.send_message(msg.chat.id, unauthorized).await?;
+    Ok(())
+}
+
+async fn check_access(
+    bot: &Bot,
+    permissions: &PermissionChecker,
+    chat: &teloxide::types::Chat,
+    user_id: Option<UserId>,
+    required: Access,
+) -> anyhow::Result<bool> {
+    match user_id {
+        Some(user_id) => permissions.allows(bot, chat, user_id, required).await,
+        None => Ok(required == Access::Anyone),
+    }
+}
+
+/// In group chats, messages are prefixed with the sender's name so the model can tell people apart
+fn name_message(group_chat: bool, username: &str, text: &str) -> String {
+    if group_chat {
         format!("{username}: {text}")
     } else {
         text.into()
-    };
-    conversation
-        .messages
-        .push(ChatMessage::new(named_message, Some(username)));
-    if group_chat && !default_backend.my_turn(conversation).await? {
-        println!("Bot chose not to reply");
-        return Ok(state);
-    }
-    let response = typing_while(bot, chat_id, default_backend.reply(conversation)).await?;
-    conversation
-        .messages
-        .push(ChatMessage::new(response.clone(), None));
-    println!("BOT: {response}");
-    bot.send_message(chat_id, response).await?;
-    Ok(state)
+    }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // Set up the Telegram bot API
-    let Ok(tg_bot_token) = std::env::var("TG_BOT_TOKEN") else {
-        eprintln!("Need telegram bot token TG_BOT_TOKEN in environment");
-        anyhow::bail!("Need telegram bot token TG_BOT_TOKEN in environment");
-    };
-    let bot = Bot::new(tg_bot_token);
+/// Shared by all handlers, everything besides the per-chat and per-user state
+struct BotContext {
+    me: Me,
+    /// Swapped as a whole when the config is reloaded
+    settings: RwLock<Settings>,
+    permissions: PermissionChecker,
+    access: Arc<Mutex<AccessList>>,
+    usage: Arc<Mutex<UsageStore>>,
+    rate_limiter: RateLimiter,
+    /// Debounced replies waiting for the chat to go quiet, or being generated
+    pending_replies: Mutex<HashMap<ChatId, PendingReply>>,
+    /// Held from loading a chat's state until the changes to it are stored, so nothing else
+    /// changes the chat in between only to be overwritten
+    chat_locks: Mutex<HashMap<ChatId, Arc<tokio::sync::Mutex<()>>>>,
+    /// Chats with an update being handled, and how many
+    in_flight: Mutex<HashMap<ChatId, usize>>,
+}
 
-    // Set up the OLLAMA model
-    //let ollama = Ollama::new("http://localhost".into(), 11434);
+/// Counts an update of the chat as in flight until dropped
+struct Handling {
+    ctx: Ctx,
+    chat_id: ChatId,
+}
+
+impl Drop for Handling {
+    fn drop(&mut self) {
+        let mut in_flight = self.ctx.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.chat_id) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.chat_id);
+            }
+        }
+    }
+}
+
+/// The parts of the config that can change while the bot is running
+struct Settings {
+    config: Config,
+    backend: Backend,
+    quotas: Quotas,
+}
 
-    // Set up OpenAI client
-    //let openai_config = OpenAIConfig::new().with_api_base("http://localhost:5000/v1");
-    //let openai_client = OpenAIClient::with_config(openai_config);
+impl Settings {
+    fn new(config: Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            backend: config.default_backend()?,
+            quotas: Quotas {
+                user: config.limits.user_quota,
+                chat: config.limits.chat_quota,
+            },
+            config,
+        })
+    }
+}
 
-    let openai_models = vec!["turboderp_Llama-3-70B-Instruct-exl2_5.0bpw".to_string()]; // Add more as needed
+/// Why the bot won't generate anything right now
+struct Refusal {
+    text: String,
+    /// Whether to tell the user, they're only told to slow down once
+    notify: bool,
+    /// When a message can be answered again, if it's only rate limited
+    retry_after: Option<Duration>,
+}
 
-    println!("OpenAI models: {openai_models:?}");
+impl BotContext {
+    /// Also notes the backend and model on the current update's span
+    fn backend(&self) -> Backend {
+        let settings = self.settings.read().unwrap();
+        let span = tracing::Span::current();
+        span.record("backend", settings.config.default_backend.as_str());
+        span.record("model", settings.backend.model_name());
+        settings.backend.clone()
+    }
 
-    let chats = std::fs::read("chats.json")
-        .ok()
-        .and_then(|conts| serde_json::from_slice::<HashMap<ChatId, UserState>>(&conts).ok())
-        .inspect(|chats| println!("Loaded {} chats!", chats.len()))
-        .unwrap_or_default();
-    let chats = Arc::new(Mutex::new(chats));
-    let interval_saver_chats = Arc::clone(&chats);
-    let final_save_chats = Arc::clone(&chats);
+    fn quotas(&self) -> Quotas {
+        self.settings.read().unwrap().quotas
+    }
 
-    let mut interval_saver = tokio::time::interval(Duration::from_secs(300));
-    tokio::task::spawn(async move {
+    fn retention(&self) -> Retention {
+        self.settings.read().unwrap().config.retention
+    }
+
+    fn is_owner(&self, user_id: Option<UserId>) -> bool {
+        user_id.is_some_and(|id| self.permissions.is_owner(id))
+    }
+
+    fn is_allowed(&self, user_id: Option<UserId>, chat_id: ChatId) -> bool {
+        self.is_owner(user_id) || self.access.lock().unwrap().is_allowed(user_id, chat_id)
+    }
+
+    /// Why the bot won't answer, if the user or the chat has used up its quota. The owner has
+    /// no limits.
+    fn quota_exceeded(&self, user_id: Option<UserId>, chat_id: ChatId) -> Option<String> {
+        if self.is_owner(user_id) {
+            return None;
+        }
+        let quotas = self.quotas();
+        let usage = self.usage.lock().unwrap();
+        if let Some(limit) = user_id
+            .and_then(|id| usage.users.get(&id))
+            .and_then(|record| quotas.user.exceeded(record))
+        {
+            return Some(format!(
+                "Sorry, you've reached your {limit}. See /usage for details."
+            ));
+        }
+        let limit = usage
+            .chats
+            .get(&chat_id)
+            .and_then(|record| quotas.chat.exceeded(record))?;
+        Some(format!(
+            "Sorry, this chat has reached its {limit}. See /usage for details."
+        ))
+    }
+
+    /// Checks the quotas, then takes a turn from the rate limits. The owner has no limits.
+    fn refusal(&self, user_id: Option<UserId>, chat_id: ChatId) -> Option<Refusal> {
+        if let Some(text) = self.quota_exceeded(user_id, chat_id) {
+            return Some(Refusal {
+                text,
+                notify: true,
+                retry_after: None,
+            });
+        }
+        if self.is_owner(user_id) {
+            return None;
+        }
+        let limited = self.rate_limiter.check(user_id, chat_id)?;
+        Some(Refusal {
+            text: SLOW_DOWN_MESSAGE.into(),
+            notify: limited.notify,
+            retry_after: Some(limited.retry_after),
+        })
+    }
+
+    /// Cancels the chat's debounced reply, for a new message that will be answered along with
+    /// it, even while the reply is being written. One that is already being sent is let finish
+    /// instead, so it isn't lost after being sent. Either way this waits until it has stopped.
+    async fn cancel_pending_reply(&self, chat_id: ChatId) {
+        let pending = self.pending_replies.lock().unwrap().remove(&chat_id);
+        if let Some(PendingReply {
+            handle: Some(handle),
+            started,
+        }) = pending
+        {
+            if !started {
+                handle.abort();
+            }
+            let _ = handle.await;
+        }
+    }
+
+    /// Marks the chat's debounced reply as being sent, so it isn't cancelled anymore. False if
+    /// it was cancelled already.
+    fn start_sending(&self, chat_id: ChatId) -> bool {
+        match self.pending_replies.lock().unwrap().get_mut(&chat_id) {
+            Some(pending) => {
+                pending.started = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Waits until nothing else is working on the chat's state, see `chat_locks`
+    async fn lock_chat(&self, chat_id: ChatId) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = Arc::clone(self.chat_locks.lock().unwrap().entry(chat_id).or_default());
+        lock.lock_owned().await
+    }
+
+    /// Changes a stored chat from outside its updates. `f` gets all the chats, to get the one
+    /// with `chat_id` or remove it.
+    async fn update_chat<T>(
+        &self,
+        chats: &Chats,
+        chat_id: ChatId,
+        f: impl FnOnce(&mut HashMap<ChatId, UserState>) -> T,
+    ) -> T {
+        // Updates being handled write their copy of the chat back, wait for them
+        let _lock = self.lock_chat(chat_id).await;
+        f(&mut chats.lock().unwrap())
+    }
+
+    fn handling(self: &Arc<Self>, chat_id: ChatId) -> Handling {
+        *self.in_flight.lock().unwrap().entry(chat_id).or_default() += 1;
+        Handling {
+            ctx: Arc::clone(self),
+            chat_id,
+        }
+    }
+
+    /// Waits for every debounced reply to be sent, including ones rate limited replies schedule
+    /// meanwhile. They're marked as started so they still go out, nothing cancels them anymore.
+    async fn finish_pending_replies(&self) {
         loop {
-            interval_saver.tick().await;
-            let chats_ser = serde_json::to_string(&*interval_saver_chats.lock().unwrap()).unwrap();
-            if let Err(e) = tokio::fs::write("./chats.json", chats_ser).await {
-                eprintln!("WARNING: failed to autosave chats: {e}");
+            let handles: Vec<_> = self
+                .pending_replies
+                .lock()
+                .unwrap()
+                .values_mut()
+                .filter_map(|pending| {
+                    pending.started = true;
+                    pending.handle.take()
+                })
+                .collect();
+            if handles.is_empty() {
+                return;
+            }
+            for handle in handles {
+                let _ = handle.await;
             }
         }
-    });
+    }
 
-    let groq_token = std::env::var("GROQ_TOKEN").ok();
-    let default_backend = groq_token.map_or_else(
-        || {
-            println!("Using default local OpenAI backend");
-            Backend::OpenAI(OpenAIModel::new(
-                OPENAI_API_URL.into(),
-                openai_models[0].clone(),
-            ))
+    fn record_usage(&self, user_id: Option<UserId>, chat_id: ChatId, usage: Usage) {
+        let span = tracing::Span::current();
+        span.record("prompt_tokens", usage.prompt_tokens);
+        span.record("completion_tokens", usage.completion_tokens);
+        self.usage.lock().unwrap().record(user_id, chat_id, usage);
+    }
+
+    fn usage_report(&self, user_id: Option<UserId>, chat_id: ChatId, group_chat: bool) -> String {
+        let quotas = self.quotas();
+        let usage = self.usage.lock().unwrap();
+        let mut report = String::new();
+        if let Some(user_id) = user_id {
+            let quota = if self.is_owner(Some(user_id)) {
+                Quota::default()
+            } else {
+                quotas.user
+            };
+            let record = usage.users.get(&user_id).cloned().unwrap_or_default();
+            report = format!("Your usage:\n{}", record.report(&quota));
+        }
+        if group_chat {
+            let record = usage.chats.get(&chat_id).cloned().unwrap_or_default();
+            report = format!(
+                "{report}\n\nThis chat's usage:\n{}",
+                record.report(&quotas.chat)
+            );
+        }
+        report.trim_start().to_string()
+    }
+}
+type Ctx = Arc<BotContext>;
+
+async fn handle_update(
+    bot: Bot,
+    msg: Message,
+    edited: bool,
+    chats: Chats,
+    memories: Memories,
+    ctx: Ctx,
+) {
+    let chat_id = msg.chat.id;
+    let user_id = msg.from().map(|user| user.id);
+    if !ctx.is_allowed(user_id, chat_id) {
+        if !edited {
+            if let Err(e) = handle_unauthorized(&bot, &ctx, &msg).await {
+                error!("Error on handle_unauthorized: {e:?}");
+            }
+        }
+        return;
+    }
+    let _handling = ctx.handling(chat_id);
+    // A new message is answered along with the ones waiting for a reply. Anything else leaves the
+    // reply alone, it still comes once the chat is quiet.
+    let new_text = msg.text().filter(|text| !edited && !text.starts_with('/'));
+    if new_text.is_some() {
+        ctx.cancel_pending_reply(chat_id).await;
+    }
+    let _chat = ctx.lock_chat(chat_id).await;
+    let (mut state, memory) = load_state(&chats, &memories, chat_id, user_id);
+    let debounce = state.debounce(bot::is_group_chat(&msg.chat));
+    if let Some(text) = new_text {
+        if !debounce.is_zero() {
+            let username = msg
+                .from()
+                .map_or_else(|| "UNKNOWN".into(), teloxide::types::User::full_name);
+            info!(from = %username, text = %logging::content(text), "Message");
+            push_user_message(&mut state, &msg, text);
+            chats.lock().unwrap().insert(chat_id, state);
+            schedule_reply(bot, msg, debounce, chats, memories, &ctx);
+            return;
+        }
+    }
+    let result = if edited {
+        handle_edit(&bot, &ctx, msg, state, memory).await
+    } else {
+        handle_msg(&bot, &ctx, msg, state, memory, &chats, &memories).await
+    };
+    finish_update(
+        &bot,
+        &chats,
+        &memories,
+        chat_id,
+        user_id,
+        result,
+        "handle_msg",
+    )
+    .await;
+}
+
+async fn handle_callback_update(
+    bot: Bot,
+    query: CallbackQuery,
+    chats: Chats,
+    memories: Memories,
+    ctx: Ctx,
+) {
+    let Some(message) = &query.message else {
+        return;
+    };
+    let chat_id = message.chat.id;
+    let user_id = query.from.id;
+    if !ctx.is_allowed(Some(user_id), chat_id) {
+        let unauthorized = ctx
+            .access
+            .lock()
+            .unwrap()
+            .unauthorized_message()
+            .to_string();
+        let _ = bot
+            .answer_callback_query(&query.id)
+            .text(unauthorized)
+            .await;
+        return;
+    }
+    let _handling = ctx.handling(chat_id);
+    if let Some(action) = query.data.as_deref().and_then(ForgetMe::parse) {
+        if let Err(e) = forget_me(&bot, &ctx, &query, message, action, &chats, &memories).await {
+            error!("Error on forget_me: {e:?}");
+            let _ = bot
+                .send_message(chat_id, format!("⚠️ Error on forget_me: {e:?}"))
+                .await;
+        }
+        return;
+    }
+    let _chat = ctx.lock_chat(chat_id).await;
+    let (state, memory) = load_state(&chats, &memories, chat_id, Some(user_id));
+    let result = handle_callback(&bot, &ctx, &query, message, state, memory).await;
+    finish_update(
+        &bot,
+        &chats,
+        &memories,
+        chat_id,
+        Some(user_id),
+        result,
+        "handle_callback",
+    )
+    .await;
+}
+
+/// Everything logged while handling an update is inside this span, which is logged with its
+/// duration when it closes. The rest of the fields are filled in along the way.
+fn update_span(kind: &'static str, chat_id: ChatId, user_id: Option<UserId>) -> tracing::Span {
+    use tracing::field::Empty;
+    tracing::info_span!(
+        "update",
+        kind,
+        chat_id = chat_id.0,
+        user_id = user_id.map(|id| id.0),
+        conversation = Empty,
+        backend = Empty,
+        model = Empty,
+        prompt_tokens = Empty,
+        completion_tokens = Empty,
+    )
+}
+
+/// Copies of the chat's state and the user's memory for a handler to work on
+fn load_state(
+    chats: &Chats,
+    memories: &Memories,
+    chat_id: ChatId,
+    user_id: Option<UserId>,
+) -> (UserState, UserMemory) {
+    let state = chats
+        .lock()
+        .unwrap()
+        .get(&chat_id)
+        .cloned()
+        .unwrap_or_default();
+    tracing::Span::current().record("conversation", state.current_conversation);
+    let memory = user_id
+        .and_then(|id| memories.lock().unwrap().get(&id).cloned())
+        .unwrap_or_default();
+    (state, memory)
+}
+
+/// Stores the state returned by a handler, or reports its error. Nothing is saved on errors, so
+/// a failed update never leaves half of its changes behind.
+async fn finish_update(
+    bot: &Bot,
+    chats: &Chats,
+    memories: &Memories,
+    chat_id: ChatId,
+    user_id: Option<UserId>,
+    result: anyhow::Result<(UserState, UserMemory)>,
+    handler: &str,
+) {
+    match result {
+        Ok((mut new_state, new_memory)) => {
+            new_state.last_active = Utc::now();
+            chats.lock().unwrap().insert(chat_id, new_state);
+            // Memories only change in private chats, writing back a group's copy could bring
+            // back ones deleted meanwhile
+            if let Some(id) = user_id.filter(|_| chat_id.is_user()) {
+                memories.lock().unwrap().insert(id, new_memory);
+            }
+        }
+        Err(e) => {
+            let err_msg = format!("⚠️ Error on {handler}: {e:?}");
+            error!("{err_msg}");
+            let _ = bot.send_message(chat_id, err_msg).await;
+        }
+    }
+}
+
+/// Memories are private, so they're only used and added to in private chats, never in groups
+/// where other members would see them
+fn private_memory(chat_id: ChatId, memory: &UserMemory) -> Option<&UserMemory> {
+    chat_id.is_user().then_some(memory)
+}
+
+fn private_memory_mut(chat_id: ChatId, memory: &mut UserMemory) -> Option<&mut UserMemory> {
+    chat_id.is_user().then_some(memory)
+}
+
+fn remember_all(memory: &mut UserMemory, facts: Vec<String>) {
+    for fact in facts {
+        if memory.remember(&fact) {
+            info!(fact = %logging::content(&fact), "Remembered");
+        }
+    }
+}
+
+fn handler_tree() -> UpdateHandler<RequestError> {
+    dptree::entry()
+        .branch(Update::filter_message().endpoint(
+            |bot: Bot, msg: Message, chats: Chats, memories: Memories, ctx: Ctx| async move {
+                let _update = METRICS.update("message");
+                let span = update_span("message", msg.chat.id, msg.from().map(|user| user.id));
+                handle_update(bot, msg, false, chats, memories, ctx)
+                    .instrument(span)
+                    .await;
+                respond(())
+            },
+        ))
+        .branch(Update::filter_edited_message().endpoint(
+            |bot: Bot, msg: Message, chats: Chats, memories: Memories, ctx: Ctx| async move {
+                let _update = METRICS.update("edit");
+                let span = update_span("edit", msg.chat.id, msg.from().map(|user| user.id));
+                handle_update(bot, msg, true, chats, memories, ctx)
+                    .instrument(span)
+                    .await;
+                respond(())
+            },
+        ))
+        .branch(Update::filter_callback_query().endpoint(
+            |bot: Bot, query: CallbackQuery, chats: Chats, memories: Memories, ctx: Ctx| async move {
+                let _update = METRICS.update("callback");
+                let span = query.message.as_ref().map_or_else(tracing::Span::none, |message| {
+                    update_span("callback", message.chat.id, Some(query.from.id))
+                });
+                handle_callback_update(bot, query, chats, memories, ctx)
+                    .instrument(span)
+                    .await;
+                respond(())
+            },
+        ))
+}
+
+async fn register_commands(bot: &Bot) -> anyhow::Result<()> {
+    bot.set_my_commands(bot::private_commands())
+        .scope(BotCommandScope::AllPrivateChats)
+        .await?;
+    bot.set_my_commands(bot::group_commands())
+        .scope(BotCommandScope::AllGroupChats)
+        .await?;
+    Ok(())
+}
+
+/// Re-reads the config and swaps in the new backends, access rules, prompts and limits. Nothing
+/// changes if the new config is invalid, and chats keep their state either way.
+fn reload_config(ctx: &BotContext, path: Option<&Path>) -> anyhow::Result<()> {
+    let settings = Settings::new(Config::load(path)?)?;
+    let changes = ctx.settings.read().unwrap().config.diff(&settings.config);
+    if changes.is_empty() {
+        info!("Config reloaded, nothing changed");
+        return Ok(());
+    }
+    for change in changes {
+        info!("Config changed: {change}");
+    }
+    let limits = &settings.config.limits;
+    ctx.rate_limiter.set_limits(
+        RateLimit {
+            per_minute: limits.user_rate_limit,
         },
-        |token| {
-            println!("Using Groq backend");
-            Backend::OpenAI(OpenAIModel::new_with_token(
-                GROQ_API_URL.into(),
-                GROQ_MODEL.into(),
-                token,
-            ))
+        RateLimit {
+            per_minute: limits.chat_rate_limit,
         },
     );
+    ctx.access
+        .lock()
+        .unwrap()
+        .apply_config(&settings.config.access);
+    logging::set_redact_content(settings.config.logging.redact_content);
+    *ctx.settings.write().unwrap() = settings;
+    Ok(())
+}
 
-    tokio::select! {
-    () = teloxide::repl(bot, move |bot: Bot, msg: Message| {
-        let ch = Arc::clone(&chats);
-        let default_backend = default_backend.clone();
-        async move {
-            let chat_id = msg.chat.id;
-            let state = ch.lock().unwrap().get(&chat_id).cloned().unwrap_or_default();
-            match handle_msg(&bot, msg, state, &default_backend.clone()).await {
-                    Ok(new_state) => {
-                        ch.lock().unwrap().insert(chat_id, new_state);
-                    },
-                    Err(e) => {
-                        let err_msg = format!("⚠️ Error on handle_msg: {e:?}");
-                        eprintln!("{err_msg}");
-                        let _ = bot.send_message(chat_id, err_msg).await;
-                    }
+/// Deletes what the retention policy doesn't keep, every `RETENTION_INTERVAL`
+async fn enforce_retention(ctx: Ctx, chats: Chats) {
+    let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        let retention = ctx.retention();
+        if retention.is_unlimited() {
+            continue;
+        }
+        let now = Utc::now();
+        let mut purged = Purged::default();
+        let mut inactive = 0;
+        let chat_ids = chats.lock().unwrap().keys().copied().collect::<Vec<_>>();
+        for chat_id in chat_ids {
+            ctx.update_chat(&chats, chat_id, |chats| {
+                let Some(state) = chats.get_mut(&chat_id) else {
+                    return;
+                };
+                if retention.is_expired(state, now) {
+                    chats.remove(&chat_id);
+                    inactive += 1;
+                } else {
+                    purged += retention.apply(state, now);
                 }
-            Ok(())
+            })
+            .await;
+        }
+        if inactive > 0 || purged.messages > 0 {
+            info!(
+                "Retention deleted {inactive} inactive chats, {} conversations and {} messages",
+                purged.conversations, purged.messages
+            );
+        }
+    }
+}
+
+/// Reloads the config on SIGHUP, or when the config file changes
+async fn watch_config(ctx: Ctx, path: Option<PathBuf>) {
+    let (reload, mut reloads) = tokio::sync::mpsc::unbounded_channel();
+    #[cfg(unix)]
+    {
+        let reload = reload.clone();
+        tokio::task::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+                warn!("Can't listen for SIGHUP, reload the config by editing it");
+                return;
+            };
+            while hangup.recv().await.is_some() {
+                let _ = reload.send("Got SIGHUP");
+            }
+        });
+    }
+    let watched = path
+        .clone()
+        .unwrap_or_else(|| config::DEFAULT_CONFIG_PATH.into());
+    tokio::task::spawn(async move {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut last_modified = modified(&watched);
+        let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now_modified = modified(&watched);
+            if now_modified != last_modified {
+                last_modified = now_modified;
+                let _ = reload.send("Config file changed");
+            }
+        }
+    });
+    while let Some(reason) = reloads.recv().await {
+        info!("{reason}, reloading config");
+        if let Err(e) = reload_config(&ctx, path.as_deref()) {
+            warn!("Keeping the old config: {e:#}");
         }
-    }) => {},
-    _ = tokio::signal::ctrl_c() => {
-            println!("Shutting down!");
+    }
+}
+
+#[derive(Parser)]
+#[command(about = "Telegram bot for chatting with an LLM")]
+struct Args {
+    /// Config file, defaults to ./config.toml if it exists
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Subcommand>,
+}
+
+#[derive(clap::Subcommand)]
+enum Subcommand {
+    /// Print a saved file (or a backup of one) as plain JSON, decrypting it with the configured
+    /// keys
+    Decrypt {
+        file: PathBuf,
+        /// Write it to this file instead
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    if let Some(Subcommand::Decrypt { file, output }) = &args.command {
+        // Only the encryption keys are needed, not the backends, and stdout is for the file
+        let config = Config::read(args.config.as_deref())?;
+        logging::init(&config.logging, true)?;
+        return storage::decrypt_file(&config, file, output.as_deref());
+    }
+    let config = Config::load(args.config.as_deref())?;
+    logging::init(&config.logging, false)?;
+
+    // Set up the Telegram bot API
+    let Some(tg_bot_token) = config::secret_from_env("TG_BOT_TOKEN")? else {
+        anyhow::bail!("Need telegram bot token TG_BOT_TOKEN or TG_BOT_TOKEN_FILE in environment");
+    };
+    let bot = Bot::new(tg_bot_token.expose_secret());
+
+    // Set up the OLLAMA model
+    //let ollama = Ollama::new("http://localhost".into(), 11434);
+
+    let storage = Storage::load(&config)?;
+    if let Some(addr) = config.metrics.listen {
+        tokio::task::spawn(metrics::serve(addr)?);
+    }
+
+    let mut interval_saver = tokio::time::interval(Duration::from_secs(config.autosave_seconds));
+    let autosave_storage = storage.clone();
+    let autosaver = tokio::task::spawn(async move {
+        loop {
+            interval_saver.tick().await;
+            autosave_storage.autosave().await;
         }
+    });
+
+    info!("Using backend `{}`", config.default_backend);
+    let user_limit = RateLimit {
+        per_minute: config.limits.user_rate_limit,
     };
+    let chat_limit = RateLimit {
+        per_minute: config.limits.chat_rate_limit,
+    };
+    let webhook_config = config.webhook.clone();
+    let settings = Settings::new(config)?;
+    if !settings.quotas.user.is_unlimited() {
+        info!("Quota per user: {}", settings.quotas.user);
+    }
+    if !settings.quotas.chat.is_unlimited() {
+        info!("Quota per chat: {}", settings.quotas.chat);
+    }
+
+    let me = bot.get_me().await?;
+    info!("Logged in as @{}", me.username());
+
+    if let Err(e) = register_commands(&bot).await {
+        warn!("Failed to register bot commands: {e}");
+    }
 
-    // Save chats
-    let chats_ser = serde_json::to_string_pretty(&*final_save_chats.lock().unwrap()).unwrap();
-    std::fs::write("chats.json", chats_ser).unwrap();
+    let owner = settings.config.owner_id.map(UserId);
+    if owner.is_none() {
+        info!("No owner_id set, owner-only commands are disabled");
+    }
+    let ctx: Ctx = Arc::new(BotContext {
+        me,
+        settings: RwLock::new(settings),
+        permissions: PermissionChecker::new(owner),
+        access: Arc::clone(&storage.access),
+        usage: Arc::clone(&storage.usage),
+        rate_limiter: RateLimiter::new(user_limit, chat_limit),
+        pending_replies: Mutex::new(HashMap::new()),
+        chat_locks: Mutex::new(HashMap::new()),
+        in_flight: Mutex::new(HashMap::new()),
+    });
+    tokio::task::spawn(watch_config(Arc::clone(&ctx), args.config));
+    tokio::task::spawn(enforce_retention(
+        Arc::clone(&ctx),
+        Arc::clone(&storage.chats),
+    ));
+
+    // Long polling unless a webhook is configured
+    let listener = match &webhook_config.url {
+        Some(url) => Some(webhook::listener(&bot, &webhook_config, url).await?),
+        None => None,
+    };
+    let chats = Arc::clone(&storage.chats);
+    let memories = Arc::clone(&storage.memories);
+    let dispatcher = Dispatcher::builder(bot.clone(), handler_tree())
+        .dependencies(dptree::deps![chats, memories, Arc::clone(&ctx)])
+        .build();
+    dispatch_until_shutdown(dispatcher, listener, &bot, &ctx).await;
 
+    // A write it already started finishes first, the final save waits for it
+    autosaver.abort();
+    let _ = autosaver.await;
+    storage.save()?;
+    info!("Saved everything, bye!");
     Ok(())
 }
 
+/// Handles updates until Ctrl-C or SIGTERM. Then stops taking new ones, and gives the updates and
+/// debounced replies in progress `shutdown_timeout_seconds` to finish. Chats still waiting after
+/// that are told to try again.
+async fn dispatch_until_shutdown(
+    mut dispatcher: Dispatcher<Bot, RequestError, DefaultKey>,
+    listener: Option<impl UpdateListener<Err = Infallible>>,
+    bot: &Bot,
+    ctx: &BotContext,
+) {
+    let shutdown = dispatcher.shutdown_token();
+    let mut dispatch = Box::pin(async {
+        match listener {
+            Some(listener) => {
+                let errors = LoggingErrorHandler::with_custom_text("Webhook error");
+                dispatcher.dispatch_with_listener(listener, errors).await;
+            }
+            None => dispatcher.dispatch().await,
+        }
+    });
+    tokio::select! {
+        () = &mut dispatch => return,
+        () = shutdown_signal() => {}
+    }
+    let timeout = Duration::from_secs(ctx.settings.read().unwrap().config.shutdown_timeout_seconds);
+    info!("Shutting down, waiting up to {timeout:?} for replies in progress");
+    // Only fails if the dispatcher hasn't started yet, then nothing is in progress
+    if shutdown.shutdown().is_err() {
+        return;
+    }
+    let finished = tokio::time::timeout(timeout, async {
+        dispatch.await;
+        ctx.finish_pending_replies().await;
+    })
+    .await;
+    if finished.is_err() {
+        notify_aborted(bot, ctx).await;
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("Can't listen for SIGTERM: {e}"),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Tells the chats whose replies were cut off by shutting down to try again
+async fn notify_aborted(bot: &Bot, ctx: &BotContext) {
+    let chats: Vec<ChatId> = ctx.in_flight.lock().unwrap().keys().copied().collect();
+    warn!(
+        "Gave up on replies in progress in {} chats, telling them to try again",
+        chats.len()
+    );
+    let notify = chats
+        .into_iter()
+        .map(|chat_id| bot.send_message(chat_id, ABORTED_MESSAGE).send());
+    let _ = tokio::time::timeout(NOTIFY_TIMEOUT, futures_util::future::join_all(notify)).await;
+}
+
 //// Start command, pasted here jic I need the keyboard code and stuff later
 //// Yes I know this is messy, but... eh
 //if let Some(command) = text.strip_prefix("/start") {
@@ -214,12 +1688,6 @@ async fn main() -> anyhow::Result<()> {
 //                )
 //                .reply_markup(ReplyMarkup::kb_remove())
 //                .await?;
-//                bot.set_my_commands(
-//                    COMMANDS
-//                        .iter()
-//                        .map(|(cmd, desc)| BotCommand::new(*cmd, *desc)),
-//                )
-//                .await?;
 //            }
 //        }
 //        _ => {
@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use axum::http::header;
+use axum::routing::get;
+use axum::Router;
+
+use crate::models::usage::Usage;
+
+/// Collected all the time, only served when `metrics.listen` is set
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+const LATENCY_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+const SAVE_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Backend name from the config, and model
+type BackendLabels = (String, String);
+
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+    updates_in_flight: AtomicI64,
+    pending_replies: AtomicI64,
+}
+
+#[derive(Default)]
+struct Counters {
+    updates: BTreeMap<&'static str, u64>,
+    commands: BTreeMap<&'static str, u64>,
+    backend_requests: BTreeMap<BackendLabels, u64>,
+    backend_errors: BTreeMap<BackendLabels, u64>,
+    backend_latency: BTreeMap<BackendLabels, Histogram>,
+    tokens: BTreeMap<(BackendLabels, &'static str), u64>,
+    my_turn: BTreeMap<&'static str, u64>,
+    save_duration: BTreeMap<&'static str, Histogram>,
+}
+
+struct Histogram {
+    buckets: &'static [f64],
+    /// Per bucket, not cumulative. The last one is +Inf.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|&le| value <= le)
+            .unwrap_or(self.buckets.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        let les = self.buckets.iter().map(f64::to_string);
+        for (le, count) in les.chain(["+Inf".into()]).zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {cumulative}");
+    }
+}
+
+/// Keeps a gauge up by one until dropped
+pub struct GaugeGuard(&'static AtomicI64);
+
+impl GaugeGuard {
+    fn new(gauge: &'static AtomicI64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Counts an update of the given type, and keeps it in flight while the guard lives
+    pub fn update(&'static self, kind: &'static str) -> GaugeGuard {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .updates
+            .entry(kind)
+            .or_default() += 1;
+        GaugeGuard::new(&self.updates_in_flight)
+    }
+
+    /// Counts a debounced reply as pending while the guard lives
+    pub fn pending_reply(&'static self) -> GaugeGuard {
+        GaugeGuard::new(&self.pending_replies)
+    }
+
+    pub fn command(&self, name: &'static str) {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .commands
+            .entry(name)
+            .or_default() += 1;
+    }
+
+    /// A request to a backend, counted as an error if it failed
+    pub fn backend_request(&self, backend: &str, model: &str, latency: Duration, ok: bool) {
+        let labels = (backend.to_string(), model.to_string());
+        let mut counters = self.counters.lock().unwrap();
+        *counters.backend_requests.entry(labels.clone()).or_default() += 1;
+        if !ok {
+            *counters.backend_errors.entry(labels.clone()).or_default() += 1;
+        }
+        counters
+            .backend_latency
+            .entry(labels)
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn tokens(&self, backend: &str, model: &str, usage: Usage) {
+        let labels = (backend.to_string(), model.to_string());
+        let mut counters = self.counters.lock().unwrap();
+        *counters
+            .tokens
+            .entry((labels.clone(), "prompt"))
+            .or_default() += usage.prompt_tokens;
+        *counters.tokens.entry((labels, "completion")).or_default() += usage.completion_tokens;
+    }
+
+    pub fn my_turn(&self, answer: bool) {
+        let answer = if answer { "yes" } else { "no" };
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .my_turn
+            .entry(answer)
+            .or_default() += 1;
+    }
+
+    pub fn save(&self, kind: &'static str, duration: Duration) {
+        self.counters
+            .lock()
+            .unwrap()
+            .save_duration
+            .entry(kind)
+            .or_insert_with(|| Histogram::new(SAVE_BUCKETS))
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Prometheus text format
+    pub fn render(&self) -> String {
+        let counters = self.counters.lock().unwrap();
+        let mut out = String::new();
+        header(
+            &mut out,
+            "tg_bot_updates_total",
+            "counter",
+            "Updates received, by type",
+        );
+        for (kind, count) in &counters.updates {
+            let _ = writeln!(out, "tg_bot_updates_total{{type=\"{kind}\"}} {count}");
+        }
+        header(
+            &mut out,
+            "tg_bot_updates_in_flight",
+            "gauge",
+            "Updates being handled",
+        );
+        let in_flight = self.updates_in_flight.load(Ordering::Relaxed);
+        let _ = writeln!(out, "tg_bot_updates_in_flight {in_flight}");
+        header(
+            &mut out,
+            "tg_bot_pending_replies",
+            "gauge",
+            "Debounced replies waiting or being generated",
+        );
+        let pending = self.pending_replies.load(Ordering::Relaxed);
+        let _ = writeln!(out, "tg_bot_pending_replies {pending}");
+        header(
+            &mut out,
+            "tg_bot_commands_total",
+            "counter",
+            "Commands executed",
+        );
+        for (command, count) in &counters.commands {
+            let _ = writeln!(
+                out,
+                "tg_bot_commands_total{{command=\"{command}\"}} {count}"
+            );
+        }
+        header(
+            &mut out,
+            "tg_bot_backend_requests_total",
+            "counter",
+            "Requests to the backends",
+        );
+        for (labels, count) in &counters.backend_requests {
+            let labels = backend_labels(labels);
+            let _ = writeln!(out, "tg_bot_backend_requests_total{{{labels}}} {count}");
+        }
+        header(
+            &mut out,
+            "tg_bot_backend_errors_total",
+            "counter",
+            "Failed requests to the backends",
+        );
+        for (labels, count) in &counters.backend_errors {
+            let labels = backend_labels(labels);
+            let _ = writeln!(out, "tg_bot_backend_errors_total{{{labels}}} {count}");
+        }
+        header(
+            &mut out,
+            "tg_bot_backend_latency_seconds",
+            "histogram",
+            "How long backend requests took",
+        );
+        for (labels, histogram) in &counters.backend_latency {
+            histogram.render(
+                &mut out,
+                "tg_bot_backend_latency_seconds",
+                &backend_labels(labels),
+            );
+        }
+        header(&mut out, "tg_bot_tokens_total", "counter", "Tokens used");
+        for ((labels, kind), count) in &counters.tokens {
+            let labels = backend_labels(labels);
+            let _ = writeln!(
+                out,
+                "tg_bot_tokens_total{{{labels},type=\"{kind}\"}} {count}"
+            );
+        }
+        header(
+            &mut out,
+            "tg_bot_my_turn_total",
+            "counter",
+            "Whether the bot decided to reply in groups",
+        );
+        for (answer, count) in &counters.my_turn {
+            let _ = writeln!(out, "tg_bot_my_turn_total{{answer=\"{answer}\"}} {count}");
+        }
+        header(
+            &mut out,
+            "tg_bot_save_duration_seconds",
+            "histogram",
+            "How long saving the chats took",
+        );
+        for (kind, histogram) in &counters.save_duration {
+            let labels = format!("kind=\"{kind}\"");
+            histogram.render(&mut out, "tg_bot_save_duration_seconds", &labels);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn backend_labels((backend, model): &BackendLabels) -> String {
+    format!(
+        "backend=\"{}\",model=\"{}\"",
+        escape(backend),
+        escape(model)
+    )
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Binds the listener right away so a bad address fails at startup, the returned future serves
+/// `/metrics` until the bot stops
+pub fn serve(addr: SocketAddr) -> anyhow::Result<impl Future<Output = ()>> {
+    let app = Router::new().route(
+        "/metrics",
+        get(|| async {
+            (
+                [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                METRICS.render(),
+            )
+        }),
+    );
+    let server = axum::Server::try_bind(&addr)?.serve(app.into_make_service());
+    tracing::info!("Serving metrics on http://{addr}/metrics");
+    Ok(async move {
+        if let Err(e) = server.await {
+            tracing::error!("Metrics server stopped: {e}");
+        }
+    })
+}
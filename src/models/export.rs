@@ -0,0 +1,391 @@
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{Branches, ChatMessage, Conversation, Role, UserState};
+
+/// What /export sends. Messages are shaped like `OpenAI` chat messages, with the time they were
+/// sent added. Only the selected branch of each conversation is included.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Export {
+    pub exported: DateTime<Utc>,
+    pub conversations: Vec<ExportedConversation>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportedConversation {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    pub messages: Vec<ExportedMessage>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportedMessage {
+    pub role: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent: Option<DateTime<Utc>>,
+}
+
+impl From<&ChatMessage> for ExportedMessage {
+    fn from(message: &ChatMessage) -> Self {
+        let (role, name) = match &message.from {
+            Role::Assistant => ("assistant", None),
+            Role::User(name) => ("user", Some(name.clone())),
+        };
+        Self {
+            role: role.into(),
+            name,
+            content: message.content.clone(),
+            sent: Some(message.sent),
+        }
+    }
+}
+
+impl From<&Conversation> for ExportedConversation {
+    fn from(conversation: &Conversation) -> Self {
+        Self {
+            name: conversation.name.clone(),
+            description: conversation.description.clone(),
+            system: conversation.system.clone(),
+            messages: conversation.messages.iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl Export {
+    pub fn new(state: &UserState) -> Self {
+        Self {
+            exported: Utc::now(),
+            conversations: state.conversations.iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec_pretty(self)
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut lines = vec![format!(
+            "# Conversations exported on {}",
+            self.exported.format("%d/%m/%Y %H:%M UTC")
+        )];
+        for conversation in &self.conversations {
+            lines.push(format!("## {}", conversation.name));
+            if let Some(description) = &conversation.description {
+                lines.push(format!("_{description}_"));
+            }
+            if let Some(system) = &conversation.system {
+                lines.push(format!("**System:** {system}"));
+            }
+            for message in &conversation.messages {
+                let from = match (&message.name, message.role.as_str()) {
+                    (Some(name), _) => name.as_str(),
+                    (None, "assistant") => "Assistant",
+                    (None, _) => "User",
+                };
+                lines.push(match message.sent {
+                    Some(sent) => format!("**{from}** ({}):", sent.format("%d/%m/%Y %H:%M")),
+                    None => format!("**{from}:**"),
+                });
+                lines.push(message.content.clone());
+            }
+        }
+        lines.join("\n\n") + "\n"
+    }
+}
+
+/// A message in an `OpenAI` `messages` array. Content can be a string or a list of parts, of
+/// which only text is supported.
+#[derive(Deserialize, Debug)]
+struct OpenAIMessage {
+    role: String,
+    #[serde(default)]
+    name: Option<String>,
+    content: Option<OpenAIContent>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum OpenAIContent {
+    Text(String),
+    Parts(Vec<OpenAIContentPart>),
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIContentPart {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: Option<String>,
+}
+
+impl OpenAIContent {
+    fn into_text(self) -> anyhow::Result<String> {
+        match self {
+            Self::Text(text) => Ok(text),
+            Self::Parts(parts) => parts
+                .into_iter()
+                .map(|part| match (part.kind.as_str(), part.text) {
+                    ("text", Some(text)) => Ok(text),
+                    (kind, _) => bail!("only text content can be imported, not {kind}"),
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+                .map(|texts| texts.join("\n")),
+        }
+    }
+}
+
+/// Reads an uploaded file as new conversations: a file from /export, or an `OpenAI` `messages`
+/// array, on its own or in a request body. User messages without a name are named after `user`,
+/// and conversations without one after `name`. Errors are meant for the user.
+pub fn import(json: &[u8], name: &str, user: &str) -> anyhow::Result<Vec<Conversation>> {
+    let value: Value = serde_json::from_slice(json).context("this isn't valid JSON")?;
+    let conversations = match value {
+        Value::Object(object) if object.contains_key("conversations") => {
+            let export: Export = serde_json::from_value(Value::Object(object))
+                .context("this doesn't look like a file from /export")?;
+            export
+                .conversations
+                .into_iter()
+                .map(|conversation| import_exported(conversation, user))
+                .collect::<anyhow::Result<Vec<_>>>()?
+        }
+        Value::Object(mut object) if object.contains_key("messages") => {
+            vec![import_openai(
+                object.remove("messages").unwrap(),
+                name,
+                user,
+            )?]
+        }
+        messages @ Value::Array(_) => vec![import_openai(messages, name, user)?],
+        _ => bail!("expected a file from /export or a `messages` array"),
+    };
+    let conversations = conversations
+        .into_iter()
+        .filter(|conversation| !conversation.messages.is_empty())
+        .collect::<Vec<_>>();
+    if conversations.is_empty() {
+        bail!("there are no messages in it");
+    }
+    Ok(conversations)
+}
+
+fn import_exported(conversation: ExportedConversation, user: &str) -> anyhow::Result<Conversation> {
+    let messages = conversation
+        .messages
+        .into_iter()
+        .enumerate()
+        .map(|(idx, message)| {
+            let from = imported_role(&message.role, message.name, user)
+                .with_context(|| format!("message {} of \"{}\"", idx + 1, conversation.name))?;
+            Ok(imported_message(message.content, from, message.sent))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Conversation {
+        name: conversation.name,
+        messages,
+        system: conversation.system,
+        description: conversation.description,
+    })
+}
+
+fn import_openai(messages: Value, name: &str, user: &str) -> anyhow::Result<Conversation> {
+    let messages: Vec<OpenAIMessage> =
+        serde_json::from_value(messages).context("`messages` isn't a list of chat messages")?;
+    let mut conversation = Conversation {
+        name: name.into(),
+        ..Conversation::default()
+    };
+    let mut system = Vec::new();
+    for (idx, message) in messages.into_iter().enumerate() {
+        // Tool calls and their results aren't part of the conversation, assistant messages that
+        // only call tools have no content
+        if matches!(message.role.as_str(), "tool" | "function") {
+            continue;
+        }
+        let content = message
+            .content
+            .map(OpenAIContent::into_text)
+            .transpose()
+            .with_context(|| format!("message {}", idx + 1))?
+            .unwrap_or_default();
+        if matches!(message.role.as_str(), "system" | "developer") {
+            system.push(content);
+            continue;
+        }
+        let from = imported_role(&message.role, message.name, user)
+            .with_context(|| format!("message {}", idx + 1))?;
+        if content.is_empty() {
+            continue;
+        }
+        conversation
+            .messages
+            .push(imported_message(content, from, None));
+    }
+    if !system.is_empty() {
+        conversation.system = Some(system.join("\n\n"));
+    }
+    Ok(conversation)
+}
+
+fn imported_role(role: &str, name: Option<String>, user: &str) -> anyhow::Result<Role> {
+    match role {
+        "user" => Ok(Role::User(name.unwrap_or_else(|| user.into()))),
+        "assistant" => Ok(Role::Assistant),
+        role => bail!("unsupported role `{role}`, only user, assistant and system are"),
+    }
+}
+
+fn imported_message(content: String, from: Role, sent: Option<DateTime<Utc>>) -> ChatMessage {
+    ChatMessage {
+        content,
+        from,
+        id: None,
+        user_id: None,
+        branches: Branches::default(),
+        sent: sent.unwrap_or_else(Utc::now),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::testing::bot;
+
+    fn import_error(json: &str) -> String {
+        format!(
+            "{:#}",
+            import(json.as_bytes(), "Imported", "Alex").unwrap_err()
+        )
+    }
+
+    #[test]
+    fn exports_import_back() {
+        let mut branched = bot("Hello!");
+        branched.branches.before = vec![vec![bot("Hi!")]];
+        let state = UserState {
+            conversations: vec![
+                Conversation {
+                    name: "First".into(),
+                    messages: vec![
+                        ChatMessage::new("Hi there".into(), Some("Sam".into())),
+                        branched,
+                    ],
+                    system: Some("Be brief.".into()),
+                    description: Some("A greeting".into()),
+                },
+                Conversation {
+                    name: "Empty".into(),
+                    ..Conversation::default()
+                },
+            ],
+            ..UserState::default()
+        };
+        let json = Export::new(&state).to_json().unwrap();
+
+        let conversations = import(&json, "Imported", "Alex").unwrap();
+        // Empty conversations are left out, and only the selected branch was exported
+        assert_eq!(conversations.len(), 1);
+        let conversation = &conversations[0];
+        assert_eq!(conversation.name, "First");
+        assert_eq!(conversation.system.as_deref(), Some("Be brief."));
+        assert_eq!(conversation.description.as_deref(), Some("A greeting"));
+        let original = &state.conversations[0].messages;
+        assert_eq!(conversation.messages.len(), 2);
+        for (imported, original) in conversation.messages.iter().zip(original) {
+            assert_eq!(imported.content, original.content);
+            assert_eq!(imported.from, original.from);
+            assert_eq!(imported.sent, original.sent);
+            assert!(imported.branches.is_empty());
+        }
+    }
+
+    #[test]
+    fn openai_messages_import() {
+        let json = r#"{"model": "gpt-4o", "messages": [
+            {"role": "system", "content": "You are terse."},
+            {"role": "developer", "content": [{"type": "text", "text": "Answer in English."}]},
+            {"role": "user", "content": "What's the weather?"},
+            {"role": "assistant", "content": null, "tool_calls": [{"id": "1", "type": "function"}]},
+            {"role": "tool", "tool_call_id": "1", "content": "{\"sunny\": true}"},
+            {"role": "assistant", "content": "Sunny."},
+            {"role": "user", "name": "Sam", "content": [
+                {"type": "text", "text": "Thanks!"},
+                {"type": "text", "text": "Bye."}
+            ]}
+        ]}"#;
+        let conversations = import(json.as_bytes(), "Imported", "Alex").unwrap();
+        assert_eq!(conversations.len(), 1);
+        let conversation = &conversations[0];
+        assert_eq!(conversation.name, "Imported");
+        assert_eq!(
+            conversation.system.as_deref(),
+            Some("You are terse.\n\nAnswer in English.")
+        );
+        let messages = conversation
+            .messages
+            .iter()
+            .map(|m| (m.from.clone(), m.content.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                (Role::User("Alex".into()), "What's the weather?"),
+                (Role::Assistant, "Sunny."),
+                (Role::User("Sam".into()), "Thanks!\nBye."),
+            ]
+        );
+
+        // A bare array works too
+        let bare = r#"[{"role": "user", "content": "Hi"}]"#;
+        assert_eq!(
+            import(bare.as_bytes(), "Imported", "Alex").unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn invalid_files_say_what_is_wrong() {
+        assert!(import_error("not json").starts_with("this isn't valid JSON"));
+        assert_eq!(
+            import_error(r#"{"hello": "world"}"#),
+            "expected a file from /export or a `messages` array"
+        );
+        assert_eq!(
+            import_error(r#"{"messages": "hi"}"#)
+                .split(':')
+                .next()
+                .unwrap(),
+            "`messages` isn't a list of chat messages"
+        );
+        assert_eq!(
+            import_error(r#"{"messages": []}"#),
+            "there are no messages in it"
+        );
+        assert_eq!(
+            import_error(r#"[{"role": "system", "content": "Only a prompt"}]"#),
+            "there are no messages in it"
+        );
+        assert_eq!(
+            import_error(r#"[{"role": "narrator", "content": "Meanwhile"}]"#),
+            "message 1: unsupported role `narrator`, only user, assistant and system are"
+        );
+        assert_eq!(
+            import_error(
+                r#"[{"role": "user", "content": [{"type": "image_url", "image_url": {}}]}]"#
+            ),
+            "message 1: only text content can be imported, not image_url"
+        );
+        assert!(
+            import_error(r#"{"exported": "yesterday", "conversations": []}"#)
+                .starts_with("this doesn't look like a file from /export")
+        );
+    }
+}
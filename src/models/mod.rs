@@ -1,6 +1,14 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use teloxide::types::{MessageId, UserId};
 
-use crate::ai::Model;
+use crate::ai::{Model, Reply};
+
+pub mod export;
+pub mod retention;
+#[cfg(test)]
+pub mod testing;
+pub mod usage;
 
 #[derive(Clone, Debug)]
 pub enum Backend {
@@ -8,10 +16,36 @@ pub enum Backend {
     OpenAI(crate::ai::openai::OpenAIModel),
 }
 
+impl Backend {
+    pub fn model_name(&self) -> &str {
+        match self {
+            Backend::OpenAI(model) => model.model(),
+        }
+    }
+}
+
 impl Model for Backend {
-    async fn reply(&self, conversation: &Conversation) -> anyhow::Result<String> {
+    async fn reply(
+        &self,
+        conversation: &Conversation,
+        memory: Option<&UserMemory>,
+    ) -> anyhow::Result<Reply> {
         match self {
-            Backend::OpenAI(model) => model.reply(conversation).await,
+            Backend::OpenAI(model) => model.reply(conversation, memory).await,
+        }
+    }
+    async fn reply_with_instruction(
+        &self,
+        conversation: &Conversation,
+        memory: Option<&UserMemory>,
+        instruction: &str,
+    ) -> anyhow::Result<Reply> {
+        match self {
+            Backend::OpenAI(model) => {
+                model
+                    .reply_with_instruction(conversation, memory, instruction)
+                    .await
+            }
         }
     }
     async fn description(&self, conversation: &Conversation) -> anyhow::Result<String> {
@@ -19,7 +53,7 @@ impl Model for Backend {
             Backend::OpenAI(model) => model.description(conversation).await,
         }
     }
-    async fn my_turn(&self, conversation: &Conversation) -> anyhow::Result<bool> {
+    async fn my_turn(&self, conversation: &Conversation) -> anyhow::Result<(bool, usage::Usage)> {
         match self {
             Backend::OpenAI(model) => model.my_turn(conversation).await,
         }
@@ -36,29 +70,183 @@ pub enum Role {
 pub struct ChatMessage {
     pub content: String,
     pub from: Role,
+    /// Telegram message this was sent as, if any
+    #[serde(default)]
+    pub id: Option<MessageId>,
+    /// Who sent it, for user messages saved since this was tracked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<UserId>,
+    /// Alternative versions of the conversation starting at this message
+    #[serde(default, skip_serializing_if = "Branches::is_empty")]
+    pub branches: Branches,
+    /// Messages saved before this was tracked count from when they were loaded
+    #[serde(default = "Utc::now")]
+    pub sent: DateTime<Utc>,
+}
+
+/// Sibling branches of a message in the conversation tree. Each branch is the rest of the
+/// conversation starting at the sibling, so it replaces this message and everything after it.
+/// Only the message on the selected path holds these; stashed branches carry their own nested
+/// branches further down.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct Branches {
+    pub before: Vec<Vec<ChatMessage>>,
+    pub after: Vec<Vec<ChatMessage>>,
+}
+
+impl Branches {
+    pub fn is_empty(&self) -> bool {
+        self.before.is_empty() && self.after.is_empty()
+    }
+    /// 1-based position of the selected branch, and the total number of branches
+    pub fn position(&self) -> (usize, usize) {
+        (
+            self.before.len() + 1,
+            self.before.len() + 1 + self.after.len(),
+        )
+    }
 }
 impl ChatMessage {
     pub fn new(content: String, from: Option<String>) -> Self {
         Self {
             content,
             from: from.map_or(Role::Assistant, Role::User),
+            id: None,
+            user_id: None,
+            branches: Branches::default(),
+            sent: Utc::now(),
         }
     }
+    pub fn with_id(mut self, id: MessageId) -> Self {
+        self.id = Some(id);
+        self
+    }
+    pub fn with_user_id(mut self, user_id: Option<UserId>) -> Self {
+        self.user_id = user_id;
+        self
+    }
+}
+
+/// Maximum number of facts kept about a single user
+pub const MAX_MEMORIES: usize = 50;
+
+/// Long-term facts about a user, shared across all of their conversations
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct UserMemory {
+    pub facts: Vec<String>,
+}
+
+impl UserMemory {
+    /// Returns false if the fact was not added, either because it is already known or because
+    /// the memory is full.
+    pub fn remember(&mut self, fact: &str) -> bool {
+        let fact = fact.trim();
+        if fact.is_empty()
+            || self.facts.len() >= MAX_MEMORIES
+            || self.facts.iter().any(|f| f.eq_ignore_ascii_case(fact))
+        {
+            return false;
+        }
+        self.facts.push(fact.into());
+        true
+    }
+    /// Appends the remembered facts to the given system message
+    pub fn augment_system(&self, system: Option<&str>) -> Option<String> {
+        if self.facts.is_empty() {
+            return system.map(Into::into);
+        }
+        let facts = self
+            .facts
+            .iter()
+            .map(|f| format!("- {f}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let memory = format!("Things you remember about the user you are talking to:\n{facts}");
+        Some(match system {
+            Some(system) => format!("{system}\n\n{memory}"),
+            None => memory,
+        })
+    }
 }
 
 // new model with characters n conversations n stuff
 
 // TODO: probably shouldn't have to be `Clone`
-#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+/// Fields missing from older saves get their defaults, see `storage` for the file format
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct UserState {
     //pub backend: Option<Backend>,
     pub conversations: Vec<Conversation>,
     pub current_conversation: Option<usize>,
     //pub characters: Vec<Character>,
     pub ui_state: UIState,
+    /// Commands only group admins may use, `None` for the defaults
+    pub restricted_commands: Option<Vec<String>>,
+    /// Seconds to wait for more messages before replying, `None` for the default
+    pub debounce_seconds: Option<u64>,
+    /// Last time anyone used the bot in this chat
+    #[serde(default = "Utc::now")]
+    pub last_active: DateTime<Utc>,
 }
 
+impl Default for UserState {
+    fn default() -> Self {
+        Self {
+            conversations: Vec::new(),
+            current_conversation: None,
+            ui_state: UIState::default(),
+            restricted_commands: None,
+            debounce_seconds: None,
+            last_active: Utc::now(),
+        }
+    }
+}
+
+/// Debounce in private chats that haven't set their own. Groups reply right away by default.
+pub const DEFAULT_DEBOUNCE_SECONDS: u64 = 2;
+
 impl UserState {
+    /// How long to wait for the user to stop typing before replying. Zero means right away.
+    pub fn debounce(&self, group_chat: bool) -> std::time::Duration {
+        let default = if group_chat {
+            0
+        } else {
+            DEFAULT_DEBOUNCE_SECONDS
+        };
+        std::time::Duration::from_secs(self.debounce_seconds.unwrap_or(default))
+    }
+
+    /// Finds the conversation index and message index of a Telegram message, preferring the
+    /// current conversation (forks share message IDs with the conversation they came from)
+    pub fn find_message(&self, id: MessageId) -> Option<(usize, usize)> {
+        self.current_conversation
+            .into_iter()
+            .chain(0..self.conversations.len())
+            .find_map(|conv_idx| {
+                let conversation = self.conversations.get(conv_idx)?;
+                conversation
+                    .messages
+                    .iter()
+                    .position(|m| m.id == Some(id))
+                    .map(|msg_idx| (conv_idx, msg_idx))
+            })
+    }
+    /// Removes everything the user said from all conversations, branches included. Messages
+    /// saved before senders were tracked are matched by `name` if given, and kept otherwise.
+    /// Returns how many were removed.
+    pub fn remove_user_messages(&mut self, user_id: UserId, name: Option<&str>) -> usize {
+        let is_theirs = |m: &ChatMessage| match m.user_id {
+            Some(id) => id == user_id,
+            None => name.is_some_and(|name| m.from == Role::User(name.into())),
+        };
+        self.conversations
+            .iter_mut()
+            .map(|conversation| remove_messages_where(&mut conversation.messages, &is_theirs))
+            .sum()
+    }
     pub fn get_current_conversation(&mut self) -> Option<&mut Conversation> {
         self.current_conversation
             .and_then(|idx| self.conversations.get_mut(idx))
@@ -75,6 +263,26 @@ impl UserState {
     }
 }
 
+/// Removes matching messages from `messages` and the branches they hold. Only the bot's replies
+/// hold branches, so removing a user message never drops another branch.
+fn remove_messages_where(
+    messages: &mut Vec<ChatMessage>,
+    matches: &impl Fn(&ChatMessage) -> bool,
+) -> usize {
+    let mut removed = 0;
+    for message in messages.iter_mut() {
+        let branches = &mut message.branches;
+        for branch in branches.before.iter_mut().chain(&mut branches.after) {
+            removed += remove_messages_where(branch, matches);
+        }
+        branches.before.retain(|branch| !branch.is_empty());
+        branches.after.retain(|branch| !branch.is_empty());
+    }
+    let len = messages.len();
+    messages.retain(|m| !matches(m));
+    removed + len - messages.len()
+}
+
 #[derive(Serialize, Deserialize, Clone, Default, Debug)]
 pub enum UIState {
     #[default]
@@ -82,6 +290,7 @@ pub enum UIState {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct Conversation {
     pub name: String,
     pub messages: Vec<ChatMessage>,
@@ -104,6 +313,92 @@ impl Default for Conversation {
     }
 }
 
+impl Conversation {
+    /// Stashes the messages from `index` onwards as a sibling branch. Returns the branches that
+    /// the next message pushed in their place should hold.
+    pub fn branch_from(&mut self, index: usize) -> Branches {
+        let mut stashed = self.messages.split_off(index);
+        let Some(head) = stashed.first_mut() else {
+            return Branches::default();
+        };
+        let mut branches = std::mem::take(&mut head.branches);
+        branches.before.push(stashed);
+        branches
+    }
+    /// Selects the previous or next sibling branch of the message at `index`. Returns false if
+    /// there is none in that direction.
+    pub fn switch_branch(&mut self, index: usize, forward: bool) -> bool {
+        let Some(head) = self.messages.get_mut(index) else {
+            return false;
+        };
+        let mut branches = std::mem::take(&mut head.branches);
+        let next = if forward {
+            (!branches.after.is_empty()).then(|| branches.after.remove(0))
+        } else {
+            branches.before.pop()
+        };
+        let Some(mut next) = next.filter(|next| !next.is_empty()) else {
+            self.messages[index].branches = branches;
+            return false;
+        };
+        let current = self.messages.split_off(index);
+        if forward {
+            branches.before.push(current);
+        } else {
+            branches.after.insert(0, current);
+        }
+        next[0].branches = branches;
+        self.messages.extend(next);
+        true
+    }
+    /// Index of the last user message, where the latest exchange starts
+    pub fn last_turn_start(&self) -> Option<usize> {
+        self.messages
+            .iter()
+            .rposition(|m| matches!(m.from, Role::User(_)))
+    }
+    /// The turn starting at `index`: the message itself, plus the bot's replies if it is a user
+    /// message
+    pub fn turn_at(&self, index: usize) -> std::ops::Range<usize> {
+        let mut end = index + 1;
+        if matches!(self.messages[index].from, Role::User(_)) {
+            while self
+                .messages
+                .get(end)
+                .is_some_and(|m| m.from == Role::Assistant)
+            {
+                end += 1;
+            }
+        }
+        index..end
+    }
+    /// Removes messages along with any branches starting at them. Returns the Telegram IDs of
+    /// the bot's removed messages.
+    pub fn remove_messages(&mut self, range: std::ops::Range<usize>) -> Vec<MessageId> {
+        self.messages
+            .drain(range)
+            .filter(|m| m.from == Role::Assistant)
+            .filter_map(|m| m.id)
+            .collect()
+    }
+    /// Copy of the selected path only, with all other branches dropped
+    pub fn fork(&self, name: String) -> Self {
+        Self {
+            name,
+            messages: self
+                .messages
+                .iter()
+                .map(|m| ChatMessage {
+                    branches: Branches::default(),
+                    ..m.clone()
+                })
+                .collect(),
+            system: self.system.clone(),
+            description: self.description.clone(),
+        }
+    }
+}
+
 impl std::fmt::Display for Conversation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let Some(desc) = &self.description {
@@ -114,6 +409,110 @@ impl std::fmt::Display for Conversation {
     }
 }
 
+#[allow(dead_code)]
 pub struct Character {
     pub name: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+
+    /// Does what /redo does, with `reply` as the new reply
+    fn redo(conversation: &mut Conversation, reply: &str) {
+        let branches = conversation.branch_from(conversation.messages.len() - 1);
+        let mut reply = bot(reply);
+        reply.branches = branches;
+        conversation.messages.push(reply);
+    }
+
+    #[test]
+    fn redo_then_switching_keeps_positions() {
+        let mut conversation = conversation(vec![user("hi"), bot("first")]);
+        redo(&mut conversation, "second");
+        redo(&mut conversation, "third");
+        assert_eq!(contents(&conversation.messages), ["hi", "third"]);
+        assert_eq!(conversation.messages[1].branches.position(), (3, 3));
+
+        assert!(conversation.switch_branch(1, false));
+        assert_eq!(contents(&conversation.messages), ["hi", "second"]);
+        assert_eq!(conversation.messages[1].branches.position(), (2, 3));
+        assert!(conversation.switch_branch(1, false));
+        assert_eq!(contents(&conversation.messages), ["hi", "first"]);
+        assert_eq!(conversation.messages[1].branches.position(), (1, 3));
+        assert!(!conversation.switch_branch(1, false));
+        assert_eq!(conversation.messages[1].branches.position(), (1, 3));
+
+        assert!(conversation.switch_branch(1, true));
+        assert!(conversation.switch_branch(1, true));
+        assert_eq!(contents(&conversation.messages), ["hi", "third"]);
+        assert_eq!(conversation.messages[1].branches.position(), (3, 3));
+        assert!(!conversation.switch_branch(1, true));
+        assert_eq!(contents(&conversation.messages), ["hi", "third"]);
+    }
+
+    #[test]
+    fn branches_keep_the_rest_of_their_conversation() {
+        let mut conversation =
+            conversation(vec![user("hi"), bot("first"), user("more"), bot("again")]);
+        let branches = conversation.branch_from(1);
+        let mut reply = bot("second");
+        reply.branches = branches;
+        conversation.messages.push(reply);
+        assert_eq!(contents(&conversation.messages), ["hi", "second"]);
+
+        assert!(conversation.switch_branch(1, false));
+        assert_eq!(
+            contents(&conversation.messages),
+            ["hi", "first", "more", "again"]
+        );
+        assert!(conversation.switch_branch(1, true));
+        assert_eq!(contents(&conversation.messages), ["hi", "second"]);
+    }
+
+    #[test]
+    fn switching_without_branches_does_nothing() {
+        let mut conversation = conversation(vec![user("hi"), bot("only")]);
+        assert!(!conversation.switch_branch(1, true));
+        assert!(!conversation.switch_branch(1, false));
+        assert!(!conversation.switch_branch(5, false));
+        assert_eq!(contents(&conversation.messages), ["hi", "only"]);
+    }
+
+    #[test]
+    fn fork_drops_other_branches() {
+        let mut conversation = conversation(vec![user("hi"), bot("first")]);
+        redo(&mut conversation, "second");
+        let fork = conversation.fork("Fork".into());
+        assert_eq!(contents(&fork.messages), ["hi", "second"]);
+        assert!(fork.messages.iter().all(|m| m.branches.is_empty()));
+    }
+
+    #[test]
+    fn removing_a_users_messages_only_matches_names_when_asked() {
+        let alex = UserId(1);
+        let mut reply = bot("reply");
+        reply.branches.before = vec![vec![
+            bot("older reply"),
+            user("in a branch").with_user_id(Some(alex)),
+        ]];
+        let mut state = chat(vec![
+            user("untracked"),
+            reply,
+            user("tracked").with_user_id(Some(alex)),
+            user("someone else").with_user_id(Some(UserId(2))),
+        ]);
+
+        let mut by_id = state.clone();
+        assert_eq!(by_id.remove_user_messages(alex, None), 2);
+        assert_eq!(current(&by_id), ["untracked", "reply", "someone else"]);
+        assert_eq!(
+            by_id.conversations[0].messages[1].branches.before[0].len(),
+            1
+        );
+
+        assert_eq!(state.remove_user_messages(alex, Some("Alex")), 3);
+        assert_eq!(current(&state), ["reply", "someone else"]);
+    }
+}
@@ -0,0 +1,274 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+
+use super::{ChatMessage, UserState};
+
+/// How long chats are kept. `None` keeps everything.
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    /// Older messages are deleted from the start of their conversation
+    pub max_message_age_days: Option<u64>,
+    /// The oldest conversations beyond this are deleted, never the current one
+    pub max_conversations: Option<usize>,
+    /// The oldest messages beyond this are deleted from each conversation
+    pub max_messages: Option<usize>,
+    /// Chats nobody used for this long are deleted entirely
+    pub inactive_chat_days: Option<u64>,
+}
+
+/// What applying a retention policy deleted
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Purged {
+    pub messages: usize,
+    pub conversations: usize,
+}
+
+impl std::ops::AddAssign for Purged {
+    fn add_assign(&mut self, other: Self) {
+        self.messages += other.messages;
+        self.conversations += other.conversations;
+    }
+}
+
+/// Messages in `messages` and all the branches under them
+fn count_messages(messages: &[ChatMessage]) -> usize {
+    messages
+        .iter()
+        .map(|message| {
+            let branches = &message.branches;
+            1 + branches
+                .before
+                .iter()
+                .chain(&branches.after)
+                .map(|branch| count_messages(branch))
+                .sum::<usize>()
+        })
+        .sum()
+}
+
+fn days_before(now: DateTime<Utc>, days: u64) -> DateTime<Utc> {
+    let days = i64::try_from(days).unwrap_or(i64::MAX);
+    TimeDelta::try_days(days).map_or(DateTime::<Utc>::MIN_UTC, |age| now - age)
+}
+
+impl Retention {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_message_age_days.is_none()
+            && self.max_conversations.is_none()
+            && self.max_messages.is_none()
+            && self.inactive_chat_days.is_none()
+    }
+
+    /// Whether nobody has used the chat for long enough to delete it
+    pub fn is_expired(&self, state: &UserState, now: DateTime<Utc>) -> bool {
+        self.inactive_chat_days
+            .is_some_and(|days| state.last_active < days_before(now, days))
+    }
+
+    /// Deletes the messages and conversations the policy doesn't keep. Conversations left empty
+    /// are deleted too, unless they're the current one.
+    pub fn apply(&self, state: &mut UserState, now: DateTime<Utc>) -> Purged {
+        let mut purged = Purged::default();
+        for conversation in &mut state.conversations {
+            purged.messages += self.apply_to_messages(&mut conversation.messages, now);
+        }
+        let current = state.current_conversation;
+        let mut removable = (0..state.conversations.len())
+            .filter(|&idx| Some(idx) != current)
+            .collect::<Vec<_>>();
+        let excess = self
+            .max_conversations
+            .map_or(0, |max| state.conversations.len().saturating_sub(max));
+        // The oldest conversations come first
+        let mut remove = removable
+            .drain(..excess.min(removable.len()))
+            .collect::<Vec<_>>();
+        remove.extend(
+            removable
+                .into_iter()
+                .filter(|&idx| state.conversations[idx].messages.is_empty()),
+        );
+        if remove.is_empty() {
+            return purged;
+        }
+
+        let conversations = std::mem::take(&mut state.conversations);
+        state.current_conversation = None;
+        for (idx, conversation) in conversations.into_iter().enumerate() {
+            if remove.contains(&idx) {
+                purged.messages += count_messages(&conversation.messages);
+                purged.conversations += 1;
+                continue;
+            }
+            if Some(idx) == current {
+                state.current_conversation = Some(state.conversations.len());
+            }
+            state.conversations.push(conversation);
+        }
+        purged
+    }
+
+    /// Deletes the start of a conversation, or of a branch. Branches are trimmed the same way
+    /// on their own, and the ones off deleted messages go with them. Returns how many messages
+    /// were deleted, counting those in branches.
+    fn apply_to_messages(&self, messages: &mut Vec<ChatMessage>, now: DateTime<Utc>) -> usize {
+        let mut purged = 0;
+        for message in messages.iter_mut() {
+            let branches = &mut message.branches;
+            for branch in branches.before.iter_mut().chain(&mut branches.after) {
+                purged += self.apply_to_messages(branch, now);
+            }
+            branches.before.retain(|branch| !branch.is_empty());
+            branches.after.retain(|branch| !branch.is_empty());
+        }
+        let mut expired = 0;
+        if let Some(days) = self.max_message_age_days {
+            let cutoff = days_before(now, days);
+            expired = messages
+                .iter()
+                .take_while(|message| message.sent < cutoff)
+                .count();
+        }
+        if let Some(max) = self.max_messages {
+            expired = expired.max(messages.len().saturating_sub(max));
+        }
+        purged + count_messages(&messages.drain(..expired).collect::<Vec<_>>())
+    }
+
+    /// The policy, and what it means for a chat, for /retention
+    pub fn report(&self, state: &UserState) -> String {
+        if self.is_unlimited() {
+            return "Your conversations are kept until you delete them with /reset, /undo, /pop \
+                    or /delete."
+                .into();
+        }
+        let mut lines = vec!["Your conversations are kept with these limits:".to_string()];
+        if let Some(days) = self.max_message_age_days {
+            lines.push(format!("- Messages are deleted after {days} days"));
+        }
+        if let Some(max) = self.max_messages {
+            lines.push(format!("- Conversations keep their latest {max} messages"));
+        }
+        if let Some(max) = self.max_conversations {
+            lines.push(format!("- Each chat keeps its latest {max} conversations"));
+        }
+        if let Some(days) = self.inactive_chat_days {
+            lines.push(format!(
+                "- Chats are deleted entirely after {days} days without using the bot"
+            ));
+        }
+        let messages = state
+            .conversations
+            .iter()
+            .map(|conversation| conversation.messages.len())
+            .sum::<usize>();
+        lines.push(format!(
+            "\nThis chat has {} conversations with {messages} messages.",
+            state.conversations.len()
+        ));
+        if let Some(oldest) = state
+            .conversations
+            .iter()
+            .filter_map(|conversation| conversation.messages.first())
+            .map(|message| message.sent)
+            .min()
+        {
+            lines.push(format!(
+                "The oldest message is from {}.",
+                oldest.format("%d/%m/%Y")
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::testing::{bot, chat, contents};
+    use crate::models::Conversation;
+
+    fn message(content: &str, days_old: i64, now: DateTime<Utc>) -> ChatMessage {
+        let mut message = bot(content);
+        message.sent = now - TimeDelta::try_days(days_old).unwrap();
+        message
+    }
+
+    /// One conversation: a, b, c, with an old branch off b and a new one off c
+    fn state(now: DateTime<Utc>) -> UserState {
+        let mut b = message("b", 20, now);
+        b.branches.before = vec![vec![message("b1", 20, now), message("b2", 15, now)]];
+        let mut c = message("c", 1, now);
+        c.branches.after = vec![vec![
+            message("c1", 1, now),
+            message("c2", 1, now),
+            message("c3", 1, now),
+        ]];
+        chat(vec![message("a", 30, now), b, c])
+    }
+
+    #[test]
+    fn old_messages_in_branches_are_deleted() {
+        let now = Utc::now();
+        let mut state = state(now);
+        let retention = Retention {
+            max_message_age_days: Some(10),
+            ..Retention::default()
+        };
+        let purged = retention.apply(&mut state, now);
+        let messages = &state.conversations[0].messages;
+        assert_eq!(contents(messages), ["c"]);
+        assert_eq!(contents(&messages[0].branches.after[0]), ["c1", "c2", "c3"]);
+        // a, b and the branch off b
+        assert_eq!(purged.messages, 4);
+    }
+
+    #[test]
+    fn branches_keep_at_most_max_messages() {
+        let now = Utc::now();
+        let mut state = state(now);
+        let retention = Retention {
+            max_messages: Some(2),
+            ..Retention::default()
+        };
+        let purged = retention.apply(&mut state, now);
+        let messages = &state.conversations[0].messages;
+        assert_eq!(contents(messages), ["b", "c"]);
+        assert_eq!(contents(&messages[0].branches.before[0]), ["b1", "b2"]);
+        assert_eq!(contents(&messages[1].branches.after[0]), ["c2", "c3"]);
+        assert_eq!(purged.messages, 2);
+    }
+
+    #[test]
+    fn emptied_branches_are_removed() {
+        let now = Utc::now();
+        let mut state = state(now);
+        state.conversations[0].messages[1].sent = now;
+        let retention = Retention {
+            max_message_age_days: Some(10),
+            ..Retention::default()
+        };
+        let purged = retention.apply(&mut state, now);
+        let messages = &state.conversations[0].messages;
+        assert_eq!(contents(messages), ["b", "c"]);
+        assert!(messages[0].branches.is_empty());
+        assert_eq!(purged.messages, 3);
+    }
+
+    #[test]
+    fn deleted_conversations_count_their_branches() {
+        let now = Utc::now();
+        let mut state = state(now);
+        state.conversations.push(Conversation::default());
+        state.current_conversation = Some(1);
+        let retention = Retention {
+            max_conversations: Some(1),
+            ..Retention::default()
+        };
+        let purged = retention.apply(&mut state, now);
+        assert_eq!(state.conversations.len(), 1);
+        assert_eq!(purged.conversations, 1);
+        assert_eq!(purged.messages, 8);
+    }
+}
@@ -0,0 +1,56 @@
+//! Builders for tests of conversations and the commands that change them
+
+use teloxide::types::MessageId;
+
+use super::{ChatMessage, Conversation, UserState};
+
+/// A message from Alex
+pub fn user(content: &str) -> ChatMessage {
+    ChatMessage::new(content.into(), Some("Alex".into()))
+}
+
+/// A reply from the bot
+pub fn bot(content: &str) -> ChatMessage {
+    ChatMessage::new(content.into(), None)
+}
+
+pub fn conversation(messages: Vec<ChatMessage>) -> Conversation {
+    Conversation {
+        messages,
+        ..Conversation::default()
+    }
+}
+
+/// A chat with `messages` as its only conversation, which is the current one
+pub fn chat(messages: Vec<ChatMessage>) -> UserState {
+    UserState {
+        conversations: vec![conversation(messages)],
+        current_conversation: Some(0),
+        ..UserState::default()
+    }
+}
+
+/// Messages from Alex alternating with replies, sent as Telegram messages 1, 2, 3...
+pub fn exchange(contents: &[&str]) -> Vec<ChatMessage> {
+    contents
+        .iter()
+        .enumerate()
+        .map(|(idx, content)| {
+            let message = if idx % 2 == 0 {
+                user(content)
+            } else {
+                bot(content)
+            };
+            message.with_id(MessageId(i32::try_from(idx).unwrap() + 1))
+        })
+        .collect()
+}
+
+pub fn contents(messages: &[ChatMessage]) -> Vec<&str> {
+    messages.iter().map(|m| m.content.as_str()).collect()
+}
+
+/// Contents of the chat's current conversation
+pub fn current(state: &UserState) -> Vec<&str> {
+    contents(&state.conversations[state.current_conversation.unwrap()].messages)
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+
+use chrono::{Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use teloxide::types::{ChatId, UserId};
+
+/// Backend usage, as reported by the API
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Usage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    pub fn tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.requests += rhs.requests;
+        self.prompt_tokens += rhs.prompt_tokens;
+        self.completion_tokens += rhs.completion_tokens;
+    }
+}
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} requests, {} tokens ({} prompt, {} completion)",
+            self.requests,
+            self.tokens(),
+            self.prompt_tokens,
+            self.completion_tokens
+        )
+    }
+}
+
+/// Usage of one user or chat, for the current day and month and in total
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct UsageRecord {
+    pub day: NaiveDate,
+    pub today: Usage,
+    pub this_month: Usage,
+    pub total: Usage,
+}
+
+impl Default for UsageRecord {
+    fn default() -> Self {
+        Self {
+            day: Utc::now().date_naive(),
+            today: Usage::default(),
+            this_month: Usage::default(),
+            total: Usage::default(),
+        }
+    }
+}
+
+impl UsageRecord {
+    /// Resets the daily and monthly counters if they're from an earlier day or month
+    fn roll_over(&mut self) {
+        let today = Utc::now().date_naive();
+        if today == self.day {
+            return;
+        }
+        if (today.year(), today.month()) != (self.day.year(), self.day.month()) {
+            self.this_month = Usage::default();
+        }
+        self.today = Usage::default();
+        self.day = today;
+    }
+    fn add(&mut self, usage: Usage) {
+        self.roll_over();
+        self.today += usage;
+        self.this_month += usage;
+        self.total += usage;
+    }
+    /// Current counters, without counting a previous day or month
+    pub fn current(&self) -> Self {
+        let mut record = self.clone();
+        record.roll_over();
+        record
+    }
+    pub fn report(&self, quota: &Quota) -> String {
+        let record = self.current();
+        format!(
+            "Today: {}\nThis month: {}\nTotal: {}\nLimits: {quota}",
+            record.today, record.this_month, record.total
+        )
+    }
+}
+
+/// Limits for a single user or chat. `None` means unlimited.
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quota {
+    pub daily_requests: Option<u64>,
+    pub daily_tokens: Option<u64>,
+    pub monthly_requests: Option<u64>,
+    pub monthly_tokens: Option<u64>,
+}
+
+impl Quota {
+    /// Describes the first exceeded limit, if any
+    pub fn exceeded(&self, record: &UsageRecord) -> Option<String> {
+        let record = record.current();
+        let limits = [
+            (
+                self.daily_requests,
+                record.today.requests,
+                "daily",
+                "requests",
+            ),
+            (self.daily_tokens, record.today.tokens(), "daily", "tokens"),
+            (
+                self.monthly_requests,
+                record.this_month.requests,
+                "monthly",
+                "requests",
+            ),
+            (
+                self.monthly_tokens,
+                record.this_month.tokens(),
+                "monthly",
+                "tokens",
+            ),
+        ];
+        limits
+            .into_iter()
+            .find(|(limit, used, ..)| limit.is_some_and(|limit| *used >= limit))
+            .map(|(limit, _, period, what)| {
+                format!("{period} quota of {} {what}", limit.unwrap_or_default())
+            })
+    }
+    pub fn is_unlimited(&self) -> bool {
+        self.daily_requests.is_none()
+            && self.daily_tokens.is_none()
+            && self.monthly_requests.is_none()
+            && self.monthly_tokens.is_none()
+    }
+}
+
+impl std::fmt::Display for Quota {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let limits = [
+            (self.daily_requests, "requests per day"),
+            (self.daily_tokens, "tokens per day"),
+            (self.monthly_requests, "requests per month"),
+            (self.monthly_tokens, "tokens per month"),
+        ]
+        .into_iter()
+        .filter_map(|(limit, what)| Some(format!("{} {what}", limit?)))
+        .collect::<Vec<_>>();
+        if limits.is_empty() {
+            write!(f, "unlimited")
+        } else {
+            write!(f, "{}", limits.join(", "))
+        }
+    }
+}
+
+/// Limits for each user, and for each chat as a whole
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Quotas {
+    pub user: Quota,
+    pub chat: Quota,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct UsageStore {
+    pub users: HashMap<UserId, UsageRecord>,
+    pub chats: HashMap<ChatId, UsageRecord>,
+}
+
+impl UsageStore {
+    pub fn record(&mut self, user_id: Option<UserId>, chat_id: ChatId, usage: Usage) {
+        if let Some(user_id) = user_id {
+            self.users.entry(user_id).or_default().add(usage);
+        }
+        self.chats.entry(chat_id).or_default().add(usage);
+    }
+}
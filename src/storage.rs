@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use teloxide::types::{ChatId, UserId};
+
+use crate::bot::access::AccessList;
+use crate::config::{AccessConfig, Config};
+use crate::encryption::Keys;
+use crate::metrics::METRICS;
+use crate::models::usage::UsageStore;
+use crate::models::{UserMemory, UserState};
+
+pub type Chats = Arc<Mutex<HashMap<ChatId, UserState>>>;
+pub type Memories = Arc<Mutex<HashMap<UserId, UserMemory>>>;
+
+const CHATS_FILE: &str = "chats.json";
+const MEMORIES_FILE: &str = "memories.json";
+const ACCESS_FILE: &str = "access.json";
+const USAGE_FILE: &str = "usage.json";
+const BACKUP_DIR: &str = "backups";
+
+/// Version of the saved files' format. Bump it when a change to the saved types needs more than
+/// `#[serde(default)]` on a new field, and add a step to `migrate`.
+const SCHEMA_VERSION: u32 = 1;
+
+/// What every file holds, so older formats can be told apart and migrated
+#[derive(Serialize, Deserialize)]
+struct Versioned<T> {
+    version: u32,
+    data: T,
+}
+
+/// Everything the bot saves in its storage directory
+#[derive(Clone)]
+pub struct Storage {
+    dir: PathBuf,
+    backups: usize,
+    backup_interval: Duration,
+    /// Also held while taking the snapshot and writing it, so saves never overlap and the last
+    /// one written always has the newest data
+    last_backup: Arc<Mutex<Option<Instant>>>,
+    keys: Arc<Keys>,
+    pub chats: Chats,
+    pub memories: Memories,
+    pub access: Arc<Mutex<AccessList>>,
+    pub usage: Arc<Mutex<UsageStore>>,
+}
+
+impl Storage {
+    /// Fails if any of the files can't be read, so they never get overwritten with empty data
+    pub fn load(config: &Config) -> anyhow::Result<Self> {
+        let dir = config.storage_path.clone();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Couldn't create storage_path {}", dir.display()))?;
+
+        let keys = Keys::from_config(&config.encryption)?;
+        if keys.is_enabled() {
+            tracing::info!("Saved files are encrypted");
+        }
+        let chats = load_json::<HashMap<ChatId, UserState>>(&dir, CHATS_FILE, &keys)?;
+        tracing::info!("Loaded {} chats!", chats.len());
+        let memories = load_json::<HashMap<UserId, UserMemory>>(&dir, MEMORIES_FILE, &keys)?;
+        tracing::info!("Loaded memories for {} users!", memories.len());
+        let access = load_access_list(&dir, &keys, &config.access)?;
+        let usage = load_json::<UsageStore>(&dir, USAGE_FILE, &keys)?;
+
+        Ok(Self {
+            dir,
+            backups: config.backups,
+            backup_interval: Duration::from_secs(config.backup_interval_minutes * 60),
+            last_backup: Arc::new(Mutex::new(None)),
+            keys: Arc::new(keys),
+            chats: Arc::new(Mutex::new(chats)),
+            memories: Arc::new(Mutex::new(memories)),
+            access: Arc::new(Mutex::new(access)),
+            usage: Arc::new(Mutex::new(usage)),
+        })
+    }
+
+    /// Each file's contents. The locks are only held while serializing.
+    fn serialize(&self, pretty: bool) -> anyhow::Result<[(&'static str, String); 4]> {
+        fn to_json<T: Serialize>(data: &T, pretty: bool) -> anyhow::Result<String> {
+            let versioned = Versioned {
+                version: SCHEMA_VERSION,
+                data,
+            };
+            Ok(if pretty {
+                serde_json::to_string_pretty(&versioned)?
+            } else {
+                serde_json::to_string(&versioned)?
+            })
+        }
+        Ok([
+            (CHATS_FILE, to_json(&*self.chats.lock().unwrap(), pretty)?),
+            (
+                MEMORIES_FILE,
+                to_json(&*self.memories.lock().unwrap(), pretty)?,
+            ),
+            (ACCESS_FILE, to_json(&*self.access.lock().unwrap(), pretty)?),
+            (USAGE_FILE, to_json(&*self.usage.lock().unwrap(), pretty)?),
+        ])
+    }
+
+    /// Backs the files up if it's time to, then replaces them one by one with what's in memory
+    fn write(&self, pretty: bool) -> anyhow::Result<()> {
+        let mut last_backup = self.last_backup.lock().unwrap();
+        let files = self.serialize(pretty)?;
+        let back_up = self.backups > 0
+            && last_backup.is_none_or(|last| last.elapsed() >= self.backup_interval);
+        for (file, contents) in files {
+            if back_up {
+                if let Err(e) = back_up_file(&self.dir, file, self.backups) {
+                    tracing::warn!("Failed to back up {file}: {e:#}");
+                }
+            }
+            let contents = self.keys.encrypt(file, contents.into_bytes())?;
+            write_atomic(&self.dir.join(file), &contents)
+                .with_context(|| format!("Couldn't save {file}"))?;
+        }
+        if back_up {
+            *last_backup = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    pub async fn autosave(&self) {
+        let start = Instant::now();
+        let storage = self.clone();
+        let result = tokio::task::spawn_blocking(move || storage.write(false))
+            .await
+            .unwrap_or_else(|e| Err(e.into()));
+        if let Err(e) = result {
+            tracing::warn!("Failed to autosave: {e:#}");
+        }
+        METRICS.save("autosave", start.elapsed());
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let start = Instant::now();
+        self.write(true)?;
+        METRICS.save("shutdown", start.elapsed());
+        Ok(())
+    }
+}
+
+/// Loads the saved access lists, and applies the configured ones
+fn load_access_list(dir: &Path, keys: &Keys, config: &AccessConfig) -> anyhow::Result<AccessList> {
+    let mut access = load_json::<AccessList>(dir, ACCESS_FILE, keys)?;
+    access.apply_config(config);
+    if access.allowlist_only {
+        tracing::info!(
+            "Only serving {} allowed users and {} allowed chats",
+            access.allowed_users.len() + config.allowed_users.len(),
+            access.allowed_chats.len() + config.allowed_chats.len()
+        );
+    }
+    Ok(access)
+}
+
+/// A missing file loads as empty, one that can't be read or parsed is an error. Files in older
+/// formats are migrated, files from newer versions of the bot are refused.
+fn load_json<T: DeserializeOwned + Default>(
+    dir: &Path,
+    file: &str,
+    keys: &Keys,
+) -> anyhow::Result<T> {
+    let path = dir.join(file);
+    let contents = match std::fs::read(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(e).with_context(|| format!("Couldn't read {}", path.display())),
+    };
+    let corrupt = || {
+        format!(
+            "{} is corrupt, not starting so it doesn't get overwritten. Fix it, or replace it \
+             with a copy from {BACKUP_DIR}/",
+            path.display()
+        )
+    };
+    let contents = keys
+        .decrypt(Some(file), contents)
+        .with_context(|| format!("Couldn't decrypt {}", path.display()))?;
+    let value = serde_json::from_slice::<Value>(&contents).with_context(corrupt)?;
+    let Versioned { version, mut data } = unwrap_version(value).with_context(corrupt)?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "{} was saved by a newer version of the bot (format {version}, this one reads up to \
+             {SCHEMA_VERSION}), not starting so it doesn't get overwritten",
+            path.display()
+        );
+    }
+    for from in version..SCHEMA_VERSION {
+        data = migrate(from, data)
+            .with_context(|| format!("Couldn't migrate {} from format {from}", path.display()))?;
+    }
+    if version < SCHEMA_VERSION {
+        tracing::info!(
+            "Migrated {} from format {version} to {SCHEMA_VERSION}",
+            path.display()
+        );
+    }
+    serde_json::from_value(data).with_context(corrupt)
+}
+
+/// Files from before versioning hold their data directly, and count as version 0
+fn unwrap_version(value: Value) -> anyhow::Result<Versioned<Value>> {
+    match value {
+        Value::Object(object) if object.contains_key("version") && object.contains_key("data") => {
+            Ok(serde_json::from_value(Value::Object(object))?)
+        }
+        data => Ok(Versioned { version: 0, data }),
+    }
+}
+
+/// Turns the data of a file in format `from` into format `from + 1`
+fn migrate(from: u32, data: Value) -> anyhow::Result<Value> {
+    match from {
+        // Only the envelope is new. Everything added to the saved types before that (message
+        // IDs, branches, restricted commands, debounce) has a default already.
+        0 => Ok(data),
+        _ => bail!("Don't know how to migrate from format {from}"),
+    }
+}
+
+/// Writes to a temporary file next to `path` and renames it over, so a crash or a full disk
+/// leaves either the old file or the new one behind, never half of one
+fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    let result = File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Copies `file` to the backups directory with a timestamp, and deletes all but the newest
+/// `keep` copies of it
+fn back_up_file(dir: &Path, file: &str, keep: usize) -> anyhow::Result<()> {
+    let path = dir.join(file);
+    if !path.exists() {
+        return Ok(());
+    }
+    let backups = dir.join(BACKUP_DIR);
+    std::fs::create_dir_all(&backups)?;
+    let prefix = format!("{}-", file.trim_end_matches(".json"));
+    let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+    std::fs::copy(&path, backups.join(format!("{prefix}{timestamp}.json")))?;
+
+    let mut copies: Vec<PathBuf> = std::fs::read_dir(&backups)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.extension().is_some_and(|ext| ext == "json")
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(&prefix))
+        })
+        .collect();
+    // Timestamps sort by time
+    copies.sort();
+    for old in &copies[..copies.len().saturating_sub(keep)] {
+        std::fs::remove_file(old)?;
+    }
+    Ok(())
+}
+
+/// For the `decrypt` subcommand: prints a saved file (or a backup of one) in plain JSON, or
+/// writes it to `output`
+pub fn decrypt_file(config: &Config, path: &Path, output: Option<&Path>) -> anyhow::Result<()> {
+    let keys = Keys::from_config(&config.encryption)?;
+    let contents =
+        std::fs::read(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    let contents = keys
+        .decrypt(None, contents)
+        .with_context(|| format!("Couldn't decrypt {}", path.display()))?;
+    let value = serde_json::from_slice::<Value>(&contents)
+        .with_context(|| format!("{} isn't JSON", path.display()))?;
+    let pretty = serde_json::to_string_pretty(&value)?;
+    match output {
+        Some(output) => std::fs::write(output, pretty)
+            .with_context(|| format!("Couldn't write {}", output.display()))?,
+        None => println!("{pretty}"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EncryptionConfig;
+    use crate::models::Role;
+
+    fn fixtures(version: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/storage")
+            .join(version)
+    }
+
+    fn no_keys() -> Keys {
+        Keys::from_config(&EncryptionConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn loads_files_from_before_versioning() {
+        let dir = fixtures("v0");
+        let keys = no_keys();
+
+        let chats: HashMap<ChatId, UserState> = load_json(&dir, CHATS_FILE, &keys).unwrap();
+        assert_eq!(chats.len(), 2);
+        let state = &chats[&ChatId(123_456_789)];
+        assert_eq!(state.current_conversation, Some(0));
+        assert_eq!(state.debounce_seconds, None);
+        let conversation = &state.conversations[0];
+        assert_eq!(
+            conversation.system.as_deref(),
+            Some("You are a helpful assistant.")
+        );
+        assert_eq!(conversation.messages.len(), 2);
+        assert_eq!(conversation.messages[0].from, Role::User("Alex".into()));
+        assert_eq!(conversation.messages[0].id, None);
+        assert!(conversation.messages[1].branches.is_empty());
+        assert!(chats[&ChatId(-100_987_654_321)].conversations.is_empty());
+
+        let memories: HashMap<UserId, UserMemory> = load_json(&dir, MEMORIES_FILE, &keys).unwrap();
+        assert_eq!(memories[&UserId(123_456_789)].facts.len(), 2);
+
+        let access: AccessList = load_json(&dir, ACCESS_FILE, &keys).unwrap();
+        assert!(access.allowed_users.contains(&UserId(123_456_789)));
+        assert!(access.blocked_users.contains(&UserId(555)));
+        assert_eq!(access.invite_codes["abcdefghijkl"], 2);
+
+        let usage: UsageStore = load_json(&dir, USAGE_FILE, &keys).unwrap();
+        assert_eq!(usage.users[&UserId(123_456_789)].total.requests, 10);
+        assert!(usage.chats.is_empty());
+    }
+
+    #[test]
+    fn loads_version_1() {
+        let chats: HashMap<ChatId, UserState> =
+            load_json(&fixtures("v1"), CHATS_FILE, &no_keys()).unwrap();
+        let state = &chats[&ChatId(123_456_789)];
+        assert_eq!(state.debounce_seconds, Some(5));
+        assert_eq!(state.last_active.to_rfc3339(), "2026-09-01T09:31:00+00:00");
+        let messages = &state.conversations[0].messages;
+        assert_eq!(messages[0].user_id, Some(UserId(123_456_789)));
+        assert_eq!(messages[1].id, Some(teloxide::types::MessageId(11)));
+        assert_eq!(messages[1].branches.position(), (2, 2));
+    }
+
+    #[test]
+    fn missing_files_load_empty() {
+        let memories: HashMap<UserId, UserMemory> =
+            load_json(&fixtures("v1"), MEMORIES_FILE, &no_keys()).unwrap();
+        assert!(memories.is_empty());
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let error =
+            load_json::<HashMap<ChatId, UserState>>(&fixtures("future"), CHATS_FILE, &no_keys())
+                .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("was saved by a newer version of the bot (format 999"));
+    }
+}
@@ -0,0 +1,91 @@
+use std::convert::Infallible;
+
+use anyhow::{bail, Context};
+use rand::{distributions::Alphanumeric, Rng};
+use secrecy::{ExposeSecret, SecretString};
+use teloxide::prelude::*;
+use teloxide::update_listeners::webhooks::{axum_no_setup, Options};
+use teloxide::update_listeners::UpdateListener;
+
+use crate::config::{self, WebhookConfig};
+
+/// Receives updates on `config.listen` instead of polling for them. Telegram posts them to the
+/// configured URL with a secret path segment appended, and every request has to carry the secret
+/// token header. Both secrets come from `WEBHOOK_PATH_SECRET` and `WEBHOOK_SECRET_TOKEN` (or
+/// their `_FILE`s), and are made up on every start when they aren't set.
+pub async fn listener(
+    bot: &Bot,
+    config: &WebhookConfig,
+    url: &str,
+) -> anyhow::Result<impl UpdateListener<Err = Infallible>> {
+    let mut url = url::Url::parse(url).context("Invalid webhook.url")?;
+    let path_secret = match config::secret_from_env("WEBHOOK_PATH_SECRET")? {
+        Some(secret) => Some(secret),
+        None if config.set_webhook => Some(random_secret()),
+        None => None,
+    };
+    if let Some(secret) = path_secret {
+        url.path_segments_mut()
+            .map_err(|()| anyhow::anyhow!("webhook.url can't have a path"))?
+            .pop_if_empty()
+            .push(secret.expose_secret());
+    }
+    let secret_token = match config::secret_from_env("WEBHOOK_SECRET_TOKEN")? {
+        Some(token) => token,
+        None if config.set_webhook => random_secret(),
+        None => bail!("WEBHOOK_SECRET_TOKEN is needed when webhook.set_webhook is off"),
+    };
+    check_secret_token(&secret_token)?;
+
+    let options =
+        Options::new(config.listen, url.clone()).secret_token(secret_token.expose_secret().clone());
+    let (listener, stopped, router) = axum_no_setup(options);
+    let server = axum::Server::try_bind(&config.listen)
+        .with_context(|| format!("Couldn't listen on {}", config.listen))?
+        .serve(router.into_make_service())
+        .with_graceful_shutdown(stopped);
+    if config.set_webhook {
+        bot.set_webhook(url.clone())
+            .secret_token(secret_token.expose_secret())
+            .await
+            .context("Couldn't set the webhook")?;
+    }
+    let bot = bot.clone();
+    let remove_webhook = config.set_webhook;
+    tokio::task::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::error!("Webhook server stopped: {e}");
+        }
+        if remove_webhook {
+            if let Err(e) = bot.delete_webhook().await {
+                tracing::warn!("Couldn't remove the webhook: {e}");
+            }
+        }
+    });
+    tracing::info!(
+        "Receiving updates on {} at {}",
+        config.listen,
+        // The path secret is left out of the logs
+        url.origin().ascii_serialization()
+    );
+    Ok(listener)
+}
+
+fn random_secret() -> SecretString {
+    let secret: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    SecretString::new(secret)
+}
+
+/// Telegram only accepts 1-256 characters out of `A-Z`, `a-z`, `0-9`, `_` and `-`
+fn check_secret_token(token: &SecretString) -> anyhow::Result<()> {
+    let token = token.expose_secret();
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if !(1..=256).contains(&token.len()) || !token.chars().all(valid_char) {
+        bail!("WEBHOOK_SECRET_TOKEN must be 1-256 letters, digits, `_` or `-`");
+    }
+    Ok(())
+}